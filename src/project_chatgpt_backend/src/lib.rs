@@ -1,26 +1,92 @@
 use candid::{Principal, CandidType};
 use core::arch;
-use std::collections::HashMap;
-use ic_cdk_macros::{update, query};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use ic_cdk_macros::{init, inspect_message, post_upgrade, update, query};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use ic_cdk::api::time;
-use ic_llm::{AssistantMessage, ChatBuilder, ChatMessage, Model};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_llm::{AssistantMessage, ChatBuilder, ChatMessage, FunctionCall, Model, ToolCall};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableVec, StableLog, Storable};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, StableVec, StableLog, Storable};
 use std::cell::RefCell;
+use std::ops::Bound;
+use std::time::Duration;
 //use ic_stable_structures::storable::Storable;
 //use ic_stable_structures::storable::Bound;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
+/// (użytkownik, id czatu)
+type ChatKey = (Principal, [u8; 16]);
+/// (czat, id wiadomości)
+type MessageKey = (ChatKey, u32);
+/// (licznik, początek blokady jako opcjonalny f64)
+type QuotaRecord = (u32, [u8; 9]);
+/// (nazwa, liczba wiadomości, usunięty o)
+type TrashRow = ([u8; 64], u32, u64);
+/// (użytkownik, sha256(metoda, klucz))
+type IdempotencyKey = (Principal, [u8; 32]);
+/// ((użytkownik, dzień), model jako stałe bajty)
+type UsageDayKey = ((Principal, u64), [u8; 32]);
 
 const PROMPT_LIMIT: u32 = 250;
 const BLOCK_TIME_NANOS: u64 = 12 * 60 * 60 * 1_000_000_000;
+const EXPORT_MAX_RECORDS: u32 = 500;
+const DELETE_BATCH: usize = 200;
+/// Zapas instrukcji na jedno uruchomienie timera usuwającego konto.
+const DELETE_INSTRUCTION_BUDGET: u64 = 2_000_000_000;
+/// Jak długo po rozpoczęciu usunięcia konta zostaje wpis z jego postępem.
+const DELETION_TOMBSTONE_NANOS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const PURGE_BATCH: usize = 50;
+const CHANGE_FEED_MAX_PAGE: u32 = 500;
+const INTEGRITY_MAX_BATCH: u32 = 1_000;
+/// Jeden fragment uploadu musi zmieścić się w limicie ingressu (2 MB) razem z resztą argumentów.
+const UPLOAD_CHUNK_MAX: usize = 1024 * 1024;
+const MAX_OPEN_UPLOADS: usize = 8;
+const UPLOAD_EXPIRE_BATCH: usize = 50;
+/// Fragment pobierania mieści się z zapasem w limicie odpowiedzi zapytania.
+const DOWNLOAD_CHUNK_SIZE: usize = 1024 * 1024;
+const MAX_OPEN_DOWNLOADS: usize = 4;
+/// Ważność tokenu właściciela do obrazów przez HTTP (`create_image_token`).
+const IMAGE_TOKEN_TTL_NANOS: u64 = 15 * 60 * 1_000_000_000;
+const EXPORT_CONTENT_TYPE: &str = "application/candid-frames; type=vec ExportRecord";
+/// Największy obraz renderowany przez bramkę HTTP (piksele wyjściowe po skalowaniu).
+const MAX_RENDER_PIXELS: u64 = 1 << 18;
+const DEFAULT_RENDER_SCALE: u32 = 8;
+const MAX_SHARE_LINKS: usize = 100;
+/// Odpowiedź `chat`/`askaidraw`, gdy plan nie obejmuje modelu (inna niż ogólne "ERR").
+const MODEL_NOT_ALLOWED_REPLY: &str = "ERR_MODEL_NOT_ALLOWED";
+const MAX_API_KEYS: usize = 20;
+/// Wersja układu danych w pamięci stabilnej; podbijana przy każdej zmianie magazynów lub formatów.
+/// Zapisana wersja leży w `SCHEMA_VERSION_STABLE`, a `post_upgrade` migruje od niej w górę.
+/// 1 – postęp usuwania konta jako pozycja w `DataStore::ORDER`
+/// 2 – stałe identyfikatory `DataStore` (migracja postępu usuwania)
+/// 3 – tokeny właściciela do obrazów, eksport budowany przyrostowo w `DOWNLOADS_STABLE`
+/// 4 – `started_at` w rezerwacjach promptów
+const SCHEMA_VERSION: u32 = 4;
+/// Wywołanie LLM bez wyniku po tym czasie uznajemy za nieudane (trap nie zostawia śladu).
+const LLM_CALL_TIMEOUT_NANOS: u64 = 5 * 60 * 1_000_000_000;
+const ADMIN_MAX_PAGE: u32 = 50;
+/// Co tyle przeliczamy zajętość pamięci użytkownika od zera; pomiędzy tylko dodajemy zapisy.
+const STORAGE_RECOUNT_NANOS: u64 = 5 * 60 * 1_000_000_000;
+const MIB: u64 = 1024 * 1024;
+/// Znacznik w `StoredMessage.parent` oznaczający korzeń drzewa (brak rodzica).
+const NO_PARENT: u32 = u32::MAX;
 
 #[derive(Clone, CandidType, Deserialize, Serialize)]
 struct ChatMeta {
     name: String,
     id: [u8; 16],
     msg_len: u32,
+    forked_from: Option<ChatOrigin>,
+    version: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct ChatOrigin {
+    chat_id: [u8; 16],
+    msg_id: u32,
 }
 
 #[derive(Clone, CandidType, Deserialize)]
@@ -28,6 +94,63 @@ struct ChatMessageIC {
     role: String,
     content: String,
     etc: (u64, u32, u32),
+    candidates: Vec<ChatCandidate>,
+    selected: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq)]
+enum MessageRole {
+    User,
+    Assistant,
+    System,
+    Tool,
+    Image,
+}
+
+impl MessageRole {
+    fn name(self) -> &'static str {
+        match self {
+            MessageRole::User => "user",
+            MessageRole::Assistant => "assistant",
+            MessageRole::System => "system",
+            MessageRole::Tool => "tool",
+            MessageRole::Image => "image",
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct ToolArgument {
+    name: String,
+    value: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum MessageContent {
+    Text(String),
+    PixelImage { width: u32, height: u32, data: String },
+    ToolCall { id: String, name: String, arguments: Vec<ToolArgument> },
+    ToolResult { call_id: String, content: String },
+}
+
+/// Wiadomość z jawną rolą i rodzajem treści; `model` to tag modelu, który ją wygenerował.
+#[derive(Clone, CandidType, Deserialize)]
+struct TypedMessage {
+    id: u32,
+    parent: Option<u32>,
+    role: MessageRole,
+    content: MessageContent,
+    model: Option<String>,
+    timestamp: u64,
+    candidates: Vec<ChatCandidate>,
+    selected: Option<u32>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct ChatCandidate {
+    role: String,
+    content: String,
+    timestamp: u64,
 }
 
 #[derive(Clone, CandidType, Deserialize)]
@@ -35,6 +158,727 @@ struct ChatInfo {
     messages: Vec<ChatMessageIC>,
 }
 
+#[derive(Clone, CandidType, Deserialize)]
+struct ChatNode {
+    id: u32,
+    parent: Option<u32>,
+    siblings: Vec<u32>,
+    message: ChatMessageIC,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct ChatPath {
+    leaf: Option<u32>,
+    nodes: Vec<ChatNode>,
+    version: u64,
+}
+
+/// Opcje wspólne dla metod zapisujących.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+struct WriteOpts {
+    /// Klucz nadany przez klienta; ponowienie z tym samym kluczem zwraca pierwotny wynik,
+    /// `Busy`, gdy pierwsze wywołanie jeszcze trwa, a z innymi argumentami `InvalidInput`.
+    request_key: Option<String>,
+    /// Wersja czatu widziana przez klienta; inna wersja w canistrze kończy się `Conflict`.
+    expected_version: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+enum ApiError {
+    NotFound(String),
+    Conflict { expected: u64, current: u64 },
+    Busy(String),
+    InvalidInput(String),
+    Unauthorized(String),
+    /// Model nieznany albo niedostępny w planie użytkownika.
+    ModelNotAllowed(String),
+    Llm(String),
+    Internal(String),
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct TrashedChat {
+    chat: ChatMeta,
+    deleted_at: u64,
+    purge_at: u64,
+}
+
+/// Miejsce, od którego `export_my_data` wznawia eksport (wyłącznie, po tym kluczu).
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct ExportCursor {
+    store: DataStore,
+    chat_id: [u8; 16],
+    // w linkach i kluczach API: liczba już zwróconych rekordów
+    msg_id: u32,
+    // w dzienniku zużycia: ostatni zwrócony numer wpisu
+    seq: Option<u64>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+enum ExportRecord {
+    Profile { name: Option<String>, prompt_count: u32, blocked_since: Option<u64> },
+    Chat { chat: ChatMeta, archived: bool, deleted_at: Option<u64>, active_leaf: Option<u32> },
+    Message { chat_id: [u8; 16], msg_id: u32, parent: Option<u32>, message: ChatMessageIC },
+    Image { chat_id: [u8; 16], msg_id: u32, width: u32, height: u32, data: String },
+    Plan { assignment: Option<TierAssignment>, quotas: Vec<QuotaStatus> },
+    ShareLink(ShareLink),
+    ApiKey(ApiKey),
+    Usage(UsageEntry),
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct ExportChunk {
+    records: Vec<ExportRecord>,
+    next: Option<ExportCursor>,
+}
+
+/// Rodzaj zmiany w dzienniku zmian użytkownika.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum ChangeKind {
+    ChatCreated { name: String, forked_from: Option<ChatOrigin> },
+    ChatRenamed { name: String },
+    ChatArchived { archived: bool },
+    ChatDeleted,
+    ChatRestored,
+    ChatPurged,
+    // nowa wiadomość staje się też aktywnym liściem
+    MessageAdded { msg_id: u32, parent: Option<u32>, role: String },
+    ActiveBranchChanged { leaf: u32 },
+    CandidateAdded { msg_id: u32, candidate: u32 },
+    CandidateSelected { msg_id: u32, candidate: u32 },
+    ImageUpdated { msg_id: u32 },
+    // `verify_integrity` poprawił czat; klient powinien wczytać go od nowa
+    ChatRepaired,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct Change {
+    seq: u64,
+    timestamp: u64,
+    chat_id: [u8; 16],
+    kind: ChangeKind,
+}
+
+/// Porcja dziennika zmian. `reset` oznacza, że część zmian po `seq` już wypadła z dziennika
+/// i klient musi wczytać stan od nowa, a potem kontynuować od `last_seq`.
+#[derive(Clone, CandidType, Deserialize)]
+struct ChangeFeed {
+    changes: Vec<Change>,
+    last_seq: u64,
+    has_more: bool,
+    reset: bool,
+}
+
+/// Magazyny z danymi użytkownika. Numery są zapisywane w postępie usuwania konta,
+/// więc nie wolno ich zmieniać ani używać ponownie; kolejność przetwarzania wyznacza `ORDER`.
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq)]
+enum DataStore {
+    Messages = 0,
+    Images = 1,
+    Candidates = 2,
+    ActiveLeaves = 3,
+    Origins = 4,
+    Chats = 5,
+    Archive = 6,
+    Trash = 7,
+    Prompts = 8,
+    Names = 9,
+    RequestKeys = 10,
+    Versions = 11,
+    Changes = 12,
+    Uploads = 13,
+    Downloads = 14,
+    ShareLinks = 15,
+    ApiKeys = 16,
+    Usage = 17,
+    Reservations = 18,
+    Tier = 19,
+    TrashOrigins = 20,
+    PurgeQueue = 21,
+    ImageTokens = 22,
+    Done = 255,
+}
+
+impl DataStore {
+    const ORDER: [DataStore; 24] = [
+        DataStore::Messages,
+        DataStore::Images,
+        DataStore::Candidates,
+        DataStore::ActiveLeaves,
+        DataStore::Origins,
+        DataStore::Versions,
+        DataStore::Chats,
+        DataStore::Archive,
+        DataStore::Trash,
+        DataStore::Prompts,
+        DataStore::Names,
+        DataStore::RequestKeys,
+        DataStore::Changes,
+        DataStore::Uploads,
+        DataStore::Downloads,
+        DataStore::ShareLinks,
+        DataStore::ApiKeys,
+        DataStore::Usage,
+        DataStore::Reservations,
+        DataStore::Tier,
+        DataStore::TrashOrigins,
+        DataStore::PurgeQueue,
+        DataStore::ImageTokens,
+        DataStore::Done,
+    ];
+
+    fn id(self) -> u8 {
+        self as u8
+    }
+
+    fn from_id(id: u8) -> DataStore {
+        DataStore::ORDER.iter().copied().find(|s| s.id() == id).unwrap_or(DataStore::Done)
+    }
+
+    fn next(self) -> DataStore {
+        let position = DataStore::ORDER.iter().position(|s| *s == self).unwrap_or(DataStore::ORDER.len() - 1);
+        DataStore::ORDER.get(position + 1).copied().unwrap_or(DataStore::Done)
+    }
+}
+
+/// Miejsce, od którego `verify_integrity` wznawia przegląd magazynu `store`.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct IntegrityCursor {
+    store: DataStore,
+    position: IntegrityPosition,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum IntegrityPosition {
+    // magazyn od początku
+    Start,
+    // wyłącznie, po tym kluczu
+    After { user: Principal, chat_id: [u8; 16], msg_id: u32 },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum IntegrityProblem {
+    // msg_len czatu różny od (najwyższy klucz wiadomości + 1)
+    MessageCountMismatch { stored: u32, actual: u32 },
+    // wiadomość z image = true bez rekordu w CHAT_IMAGES_STABLE
+    MissingImage,
+    DanglingParent { parent: u32 },
+    DanglingActiveLeaf { leaf: u32 },
+    // rekord wiadomości, obrazu lub metadanych czatu, którego nie ma w żadnej liście czatów
+    OrphanMessage,
+    OrphanImage,
+    OrphanCandidates,
+    OrphanChatData,
+}
+
+impl IntegrityProblem {
+    /// Naprawa zmienia stan istniejącego czatu (a nie usuwa osieroconego rekordu).
+    fn changes_chat(&self) -> bool {
+        matches!(
+            self,
+            IntegrityProblem::MessageCountMismatch { .. }
+                | IntegrityProblem::MissingImage
+                | IntegrityProblem::DanglingParent { .. }
+                | IntegrityProblem::DanglingActiveLeaf { .. }
+        )
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct IntegrityIssue {
+    store: DataStore,
+    user: Principal,
+    chat_id: [u8; 16],
+    msg_id: Option<u32>,
+    problem: IntegrityProblem,
+    repaired: bool,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct IntegrityReport {
+    issues: Vec<IntegrityIssue>,
+    scanned: u32,
+    next: Option<IntegrityCursor>,
+}
+
+/// Gdzie trafi treść złożona z fragmentów po `commit_upload`.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum UploadTarget {
+    NewImage { chat_id: [u8; 16], width: u32, height: u32, model: Option<String> },
+    // nowy rozmiar podaje się razem (width i height), bez niego obraz zachowuje wymiary
+    ReplaceImage { chat_id: [u8; 16], msg_id: u32, width: Option<u32>, height: Option<u32> },
+    NewMessage { chat_id: [u8; 16], role: MessageRole, model: Option<String> },
+}
+
+impl UploadTarget {
+    fn chat_id(&self) -> [u8; 16] {
+        match self {
+            UploadTarget::NewImage { chat_id, .. }
+            | UploadTarget::ReplaceImage { chat_id, .. }
+            | UploadTarget::NewMessage { chat_id, .. } => *chat_id,
+        }
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct UploadStatus {
+    upload_id: [u8; 16],
+    target: UploadTarget,
+    total_size: u64,
+    received: u64,
+    expires_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum DownloadSource {
+    // surowe dane obrazu (format pikseli frontendu)
+    Image { chat_id: [u8; 16], msg_id: u32 },
+    // wszystkie wiadomości czatu (całe drzewo) jako candid `vec TypedMessage`
+    Chat { chat_id: [u8; 16] },
+    // pełny eksport budowany w tle porcjami; ramki: 4 bajty długości (big-endian) + candid `vec ExportRecord`
+    Export,
+}
+
+/// Uchwyt do treści pobieranej fragmentami; `sha256` dotyczy całości po złożeniu.
+#[derive(Clone, CandidType, Deserialize)]
+struct DownloadHandle {
+    handle: [u8; 16],
+    source: DownloadSource,
+    content_type: String,
+    total_size: u64,
+    sha256: [u8; 32],
+    chunk_size: u32,
+    chunk_count: u32,
+    expires_at: u64,
+    // false, dopóki eksport jest budowany; rozmiar i skrót są wtedy niepełne
+    ready: bool,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    upgrade: Option<bool>,
+}
+
+/// Link udostępniający obraz (albo wszystkie obrazy czatu, gdy `msg_id` jest puste).
+/// `token` jest zwracany tylko przy tworzeniu; canister trzyma wyłącznie jego hash (`id`).
+#[derive(Clone, CandidType, Deserialize)]
+struct ShareLink {
+    id: [u8; 32],
+    token: Option<String>,
+    chat_id: [u8; 16],
+    msg_id: Option<u32>,
+    created_at: u64,
+    expires_at: Option<u64>,
+}
+
+/// Krótkotrwały token właściciela do obrazów czatu przez HTTP (`?token=`).
+#[derive(Clone, CandidType, Deserialize)]
+struct ImageToken {
+    token: String,
+    chat_id: [u8; 16],
+    expires_at: u64,
+}
+
+/// Zakres klucza API. `ReadOnly` pozwala tylko czytać; każdy zakres obejmuje też odczyt.
+#[derive(Clone, Copy, Debug, PartialEq, CandidType, Deserialize)]
+enum ApiKeyScope {
+    Chat,
+    Drawing,
+    ReadOnly,
+}
+
+/// Klucz API dla botów i integracji; `token` jest zwracany tylko przy tworzeniu.
+#[derive(Clone, CandidType, Deserialize)]
+struct ApiKey {
+    id: [u8; 32],
+    token: Option<String>,
+    name: String,
+    scope: ApiKeyScope,
+    daily_limit: Option<u32>,
+    used_today: u32,
+    created_at: u64,
+    last_used_at: Option<u64>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct StoreUsage {
+    memory_id: u8,
+    name: String,
+    pages: u64,
+    bytes: u64,
+}
+
+/// `reachable` jest puste, dopóki od startu nie było żadnego rozstrzygniętego wywołania.
+#[derive(Clone, CandidType, Deserialize)]
+struct LlmHealth {
+    reachable: Option<bool>,
+    last_attempt: Option<u64>,
+    last_success: Option<u64>,
+    last_failure: Option<u64>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct CanisterStatus {
+    version: String,
+    git_commit: Option<String>,
+    schema_version: u32,
+    started_at: u64,
+    uptime_secs: u64,
+    stores: Vec<StoreUsage>,
+    stable_memory_pages: u64,
+    heap_bytes: u64,
+    cycle_balance: u128,
+    llm: LlmHealth,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct Ban {
+    banned_at: u64,
+    banned_by: Principal,
+    reason: String,
+}
+
+/// Stan użytkownika dla operatorów; `storage_bytes` to zakodowany rozmiar wiadomości i obrazów.
+#[derive(Clone, CandidType, Deserialize)]
+struct UserSummary {
+    user: Principal,
+    name: Option<String>,
+    ban: Option<Ban>,
+    tier: Tier,
+    chats: u32,
+    archived_chats: u32,
+    trashed_chats: u32,
+    /// Przybliżona zajętość z ostatniego `check_storage`; None, jeśli od startu nic nie zapisywał.
+    storage_bytes: Option<u64>,
+    prompts_used: u32,
+    prompt_limit: u32,
+    blocked_since: Option<u64>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct UserPage {
+    users: Vec<UserSummary>,
+    next: Option<Principal>,
+}
+
+/// Zakres czasu [from, to) w nanosekundach.
+#[derive(Clone, CandidType, Deserialize)]
+struct TimeRange {
+    from: u64,
+    to: u64,
+}
+
+/// Wywołanie LLM w dzienniku zużycia; rozmiary to bajty treści promptu i odpowiedzi.
+#[derive(Clone, CandidType, Deserialize)]
+struct UsageEntry {
+    seq: u64,
+    timestamp: u64,
+    model: String,
+    endpoint: String,
+    prompt_bytes: u64,
+    response_bytes: u64,
+    success: bool,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+struct UsageStats {
+    calls: u64,
+    failures: u64,
+    prompt_bytes: u64,
+    response_bytes: u64,
+}
+
+impl UsageStats {
+    fn add(&mut self, other: &UsageStats) {
+        self.calls += other.calls;
+        self.failures += other.failures;
+        self.prompt_bytes += other.prompt_bytes;
+        self.response_bytes += other.response_bytes;
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct DailyUsage {
+    day: u64,
+    model: String,
+    usage: UsageStats,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct UsageReport {
+    daily: Vec<DailyUsage>,
+    entries: Vec<UsageEntry>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct ModelUsage {
+    model: String,
+    usage: UsageStats,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct UserUsage {
+    user: Principal,
+    models: Vec<ModelUsage>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct UsageRollup {
+    users: Vec<UserUsage>,
+    next: Option<Principal>,
+}
+
+/// Niezależne pule limitów: rozmowa, rysowanie (`askaidraw`) i wywołania narzędzi.
+#[derive(Clone, Copy, Debug, PartialEq, CandidType, Deserialize)]
+enum QuotaPool {
+    Chat,
+    Drawing,
+    Tool,
+}
+
+impl QuotaPool {
+    const ALL: [QuotaPool; 3] = [QuotaPool::Chat, QuotaPool::Drawing, QuotaPool::Tool];
+
+    fn key(self) -> u8 {
+        match self {
+            QuotaPool::Chat => 0,
+            QuotaPool::Drawing => 1,
+            QuotaPool::Tool => 2,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            QuotaPool::Chat => "chat",
+            QuotaPool::Drawing => "drawing",
+            QuotaPool::Tool => "tool",
+        }
+    }
+
+    fn limit_setting(self) -> Setting {
+        match self {
+            QuotaPool::Chat => Setting::ChatQuotaLimit,
+            QuotaPool::Drawing => Setting::DrawingQuotaLimit,
+            QuotaPool::Tool => Setting::ToolQuotaLimit,
+        }
+    }
+
+    fn window_setting(self) -> Setting {
+        match self {
+            QuotaPool::Chat => Setting::ChatQuotaWindowSecs,
+            QuotaPool::Drawing => Setting::DrawingQuotaWindowSecs,
+            QuotaPool::Tool => Setting::ToolQuotaWindowSecs,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, CandidType, Deserialize)]
+enum Tier {
+    Free,
+    Pro,
+    Team,
+}
+
+impl Tier {
+    const ALL: [Tier; 3] = [Tier::Free, Tier::Pro, Tier::Team];
+
+    fn key(self) -> u8 {
+        match self {
+            Tier::Free => 0,
+            Tier::Pro => 1,
+            Tier::Team => 2,
+        }
+    }
+
+    fn price_setting(self) -> Option<Setting> {
+        match self {
+            Tier::Free => None,
+            Tier::Pro => Some(Setting::ProTierPriceCycles),
+            Tier::Team => Some(Setting::TeamTierPriceCycles),
+        }
+    }
+}
+
+/// Limity planu. Modele to nazwy z `ic_llm::Model` (np. "llama3.1:8b").
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct TierLimits {
+    chat_quota: u32,
+    drawing_quota: u32,
+    tool_quota: u32,
+    models: Vec<String>,
+    max_chats: u32,
+    max_storage_bytes: u64,
+    max_image_side: u32,
+}
+
+impl TierLimits {
+    fn quota(&self, pool: QuotaPool) -> u32 {
+        match pool {
+            QuotaPool::Chat => self.chat_quota,
+            QuotaPool::Drawing => self.drawing_quota,
+            QuotaPool::Tool => self.tool_quota,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, CandidType, Deserialize)]
+enum TierSource {
+    Controller,
+    Payment,
+}
+
+/// Plan przypisany użytkownikowi; po `expires_at` użytkownik wraca do `Free`.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct TierAssignment {
+    tier: Tier,
+    source: TierSource,
+    assigned_at: u64,
+    expires_at: Option<u64>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct UserTier {
+    tier: Tier,
+    assignment: Option<TierAssignment>,
+    limits: TierLimits,
+}
+
+/// Stan puli; `blocked_until` jest ustawione, gdy limit został wyczerpany.
+#[derive(Clone, CandidType, Deserialize)]
+struct QuotaStatus {
+    pool: QuotaPool,
+    used: u32,
+    limit: u32,
+    window_secs: u64,
+    reserved: u32,
+    blocked_until: Option<u64>,
+}
+
+/// Prompt zarezerwowany przed wywołaniem LLM; rozlicza go wyłącznie canister w `chat`/`askaidraw`.
+#[derive(Clone, CandidType, Deserialize)]
+struct PromptReservation {
+    id: [u8; 16],
+    expires_at: u64,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+struct DeletionProgress {
+    store: DataStore,
+    removed: u64,
+    started_at: u64,
+    done: bool,
+}
+
+/// Ustawienia canistra zmieniane przez kontrolerów; wartości trzymane jako u64 w SETTINGS_STABLE.
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, PartialEq)]
+enum Setting {
+    TrashRetentionSecs,
+    IdempotencyWindowSecs,
+    IdempotencyMaxEntries,
+    ChangeLogMaxEntries,
+    MaxUserNameBytes,
+    MaxChatNameBytes,
+    MaxMessageBytes,
+    MaxImageSide,
+    MaxImagePixels,
+    MaxImageDataBytes,
+    UploadTtlSecs,
+    DownloadTtlSecs,
+    UsageLedgerMaxEntries,
+    PromptReservationTtlSecs,
+    ChatQuotaLimit,
+    ChatQuotaWindowSecs,
+    DrawingQuotaLimit,
+    DrawingQuotaWindowSecs,
+    ToolQuotaLimit,
+    ToolQuotaWindowSecs,
+    ProTierPriceCycles,
+    TeamTierPriceCycles,
+    TierPeriodSecs,
+    MaxUploadBytes,
+}
+
+impl Setting {
+    fn key(self) -> u8 {
+        match self {
+            Setting::TrashRetentionSecs => 0,
+            Setting::IdempotencyWindowSecs => 1,
+            Setting::IdempotencyMaxEntries => 2,
+            Setting::ChangeLogMaxEntries => 3,
+            Setting::MaxUserNameBytes => 4,
+            Setting::MaxChatNameBytes => 5,
+            Setting::MaxMessageBytes => 6,
+            Setting::MaxImageSide => 7,
+            Setting::MaxImagePixels => 8,
+            Setting::MaxImageDataBytes => 9,
+            Setting::UploadTtlSecs => 10,
+            Setting::DownloadTtlSecs => 11,
+            Setting::UsageLedgerMaxEntries => 12,
+            Setting::PromptReservationTtlSecs => 13,
+            Setting::ChatQuotaLimit => 14,
+            Setting::ChatQuotaWindowSecs => 15,
+            Setting::DrawingQuotaLimit => 16,
+            Setting::DrawingQuotaWindowSecs => 17,
+            Setting::ToolQuotaLimit => 18,
+            Setting::ToolQuotaWindowSecs => 19,
+            Setting::ProTierPriceCycles => 20,
+            Setting::TeamTierPriceCycles => 21,
+            Setting::TierPeriodSecs => 22,
+            Setting::MaxUploadBytes => 23,
+        }
+    }
+
+    fn default_value(self) -> u64 {
+        match self {
+            Setting::TrashRetentionSecs => 30 * 24 * 60 * 60,
+            Setting::IdempotencyWindowSecs => 24 * 60 * 60,
+            Setting::IdempotencyMaxEntries => 10_000,
+            // na użytkownika
+            Setting::ChangeLogMaxEntries => 1_000,
+            // nazwy i tak mieszczą się najwyżej w 32/64 bajtach zapisu
+            Setting::MaxUserNameBytes => 32,
+            Setting::MaxChatNameBytes => 64,
+            Setting::MaxMessageBytes => 16 * 1024,
+            Setting::MaxImageSide => 256,
+            Setting::MaxImagePixels => 256 * 256,
+            Setting::MaxImageDataBytes => 1024 * 1024,
+            // liczone od ostatniego fragmentu
+            Setting::UploadTtlSecs => 60 * 60,
+            // liczone od otwarcia pobierania
+            Setting::DownloadTtlSecs => 60 * 60,
+            // na użytkownika; dzienne sumy nie są przycinane
+            Setting::UsageLedgerMaxEntries => 1_000,
+            // nierozliczona rezerwacja wraca do puli po tym czasie
+            Setting::PromptReservationTtlSecs => 10 * 60,
+            // okno = czas blokady puli po wyczerpaniu limitu
+            Setting::ChatQuotaLimit => PROMPT_LIMIT as u64,
+            Setting::ChatQuotaWindowSecs => BLOCK_TIME_NANOS / 1_000_000_000,
+            // jeden obraz to wiele kroków rysowania
+            Setting::DrawingQuotaLimit => 1_000,
+            Setting::DrawingQuotaWindowSecs => BLOCK_TIME_NANOS / 1_000_000_000,
+            Setting::ToolQuotaLimit => 500,
+            Setting::ToolQuotaWindowSecs => BLOCK_TIME_NANOS / 1_000_000_000,
+            // cena za jeden okres `TierPeriodSecs`
+            Setting::ProTierPriceCycles => 1_000_000_000_000,
+            Setting::TeamTierPriceCycles => 5_000_000_000_000,
+            Setting::TierPeriodSecs => 30 * 24 * 60 * 60,
+            // cała treść składana z fragmentów; pojedyncze wywołanie ogranicza MaxImageDataBytes / MaxMessageBytes
+            Setting::MaxUploadBytes => 16 * MIB,
+        }
+    }
+}
+
 type ChatId = [u8; 16];
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -46,7 +890,7 @@ struct StoredImage {
 }
 
 impl Storable for StoredImage {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         candid::encode_one(self).unwrap().into()
     }
 
@@ -69,10 +913,30 @@ struct StoredMessage {
     data: Vec<u8>,
     timestamp: u64,
     image: bool,
+    // None = stary zapis, rodzicem jest poprzednia wiadomość
+    parent: Option<u32>,
+    // None = stary zapis, rola i rodzaj wynikają z `role` i `image`
+    kind: Option<StoredKind>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct StoredKind {
+    role: MessageRole,
+    model: Option<String>,
+    content: StoredContent,
+}
+
+/// Rodzaj treści; tekst i wynik narzędzia leżą w `data`, piksele w CHAT_IMAGES_STABLE.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum StoredContent {
+    Text,
+    PixelImage,
+    ToolCall { id: String, name: String, arguments: Vec<ToolArgument> },
+    ToolResult { call_id: String },
 }
 
 impl Storable for StoredMessage {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         candid::encode_one(self).unwrap().into()
     }
 
@@ -88,396 +952,4378 @@ impl Storable for StoredMessage {
         ic_stable_structures::storable::Bound::Unbounded;
 }
 
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
-
-    static USER_NAMES_STABLE: RefCell<StableBTreeMap<Principal, [u8; 32], Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)))
-        )
-    );
+/// Alternatywne odpowiedzi dla jednej wiadomości; `selected` to ta zapisana w CHAT_MESSAGES_STABLE.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct StoredCandidates {
+    candidates: Vec<StoredCandidate>,
+    selected: u32,
+}
 
-    static USER_PROMPTS_STABLE: RefCell<StableBTreeMap<Principal, (u32, [u8; 9]), Memory>> = RefCell::new(
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct StoredCandidate {
+    role: [u8; 32],
+    data: Vec<u8>,
+    timestamp: u64,
+}
+
+impl Storable for StoredCandidates {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        candid::encode_one(self).unwrap().into()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        candid::encode_one(self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct StoredResponse {
+    created_at: u64,
+    response: Vec<u8>,
+    // skrót argumentów pierwszego wywołania; ten sam klucz z innymi argumentami jest odrzucany
+    args_hash: Option<[u8; 32]>,
+    // wywołanie jeszcze trwa (czeka na await), `response` jest pusty
+    pending: Option<bool>,
+}
+
+impl Storable for StoredResponse {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        candid::encode_one(self).unwrap().into()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        candid::encode_one(self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct StoredChange {
+    timestamp: u64,
+    chat_id: [u8; 16],
+    kind: ChangeKind,
+}
+
+impl Storable for StoredChange {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        candid::encode_one(self).unwrap().into()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        candid::encode_one(self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct StoredUpload {
+    target: UploadTarget,
+    total_size: u64,
+    sha256: [u8; 32],
+    received: u64,
+    updated_at: u64,
+}
+
+impl Storable for StoredUpload {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        candid::encode_one(self).unwrap().into()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        candid::encode_one(self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct StoredDownload {
+    source: DownloadSource,
+    content_type: String,
+    total_size: u64,
+    sha256: [u8; 32],
+    chunk_count: u32,
+    created_at: u64,
+    // eksport jeszcze budowany w tle; brak = treść kompletna
+    build: Option<ExportBuild>,
+}
+
+/// Postęp budowy eksportu: kursor następnej porcji `export_user_data`.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct ExportBuild {
+    cursor: Option<ExportCursor>,
+}
+
+impl Storable for StoredDownload {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        candid::encode_one(self).unwrap().into()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        candid::encode_one(self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct StoredImageToken {
+    owner: Principal,
+    chat_id: [u8; 16],
+    expires_at: u64,
+}
+
+impl Storable for StoredImageToken {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        candid::encode_one(self).unwrap().into()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        candid::encode_one(self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct StoredShareLink {
+    owner: Principal,
+    chat_id: [u8; 16],
+    msg_id: Option<u32>,
+    created_at: u64,
+    expires_at: Option<u64>,
+}
+
+impl Storable for StoredShareLink {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        candid::encode_one(self).unwrap().into()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        candid::encode_one(self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct StoredApiKey {
+    owner: Principal,
+    created_at: u64,
+    name: Option<String>,
+    // brak zakresu = klucz sprzed zakresów, działa jak `Chat`
+    scope: Option<ApiKeyScope>,
+    daily_limit: Option<u32>,
+    usage_day: Option<u64>,
+    usage_count: Option<u32>,
+    last_used_at: Option<u64>,
+}
+
+impl StoredApiKey {
+    fn scope(&self) -> ApiKeyScope {
+        self.scope.unwrap_or(ApiKeyScope::Chat)
+    }
+
+    fn used_on(&self, day: u64) -> u32 {
+        if self.usage_day == Some(day) { self.usage_count.unwrap_or(0) } else { 0 }
+    }
+}
+
+impl Storable for StoredApiKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        candid::encode_one(self).unwrap().into()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        candid::encode_one(self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+/// Liczniki dla /metrics; etykieta to model albo rodzaj limitu.
+#[derive(Clone, Copy)]
+enum Counter {
+    LlmCalls,
+    LlmFailures,
+    QuotaBlocks,
+}
+
+impl Counter {
+    fn key(self) -> u8 {
+        match self {
+            Counter::LlmCalls => 0,
+            Counter::LlmFailures => 1,
+            Counter::QuotaBlocks => 2,
+        }
+    }
+}
+
+/// Nazwy magazynów według MemoryId (metryki i status).
+const MEMORY_STORES: &[(u8, &str)] = &[
+    (0, "user_names"),
+    (1, "user_prompts"),
+    (2, "user_chats"),
+    (3, "chat_messages"),
+    (4, "user_archive"),
+    (5, "chat_images"),
+    (6, "chat_active_leaf"),
+    (7, "chat_candidates"),
+    (8, "chat_origin"),
+    (9, "user_trash"),
+    (10, "settings"),
+    (11, "account_deletion"),
+    (12, "idempotency"),
+    (13, "idempotency_by_time"),
+    (14, "chat_versions"),
+    (15, "change_log"),
+    (16, "change_seq"),
+    (17, "uploads"),
+    (18, "upload_chunks"),
+    (19, "downloads"),
+    (20, "download_chunks"),
+    (21, "share_links"),
+    (22, "share_links_by_user"),
+    (23, "api_keys"),
+    (24, "api_keys_by_user"),
+    (25, "counters"),
+    (26, "banned_users"),
+    (27, "prompt_limits"),
+    (28, "usage_ledger"),
+    (29, "usage_seq"),
+    (30, "usage_daily"),
+    (31, "prompt_reservations"),
+    (32, "quota_pools"),
+    (33, "tier_limits"),
+    (34, "user_tiers"),
+    (35, "trash_from_archive"),
+    (36, "purge_queue"),
+    (37, "image_tokens"),
+    (38, "schema_version"),
+];
+
+impl Storable for Ban {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        candid::encode_one(self).unwrap().into()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        candid::encode_one(self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct StoredUsage {
+    timestamp: u64,
+    model: String,
+    endpoint: String,
+    prompt_bytes: u64,
+    response_bytes: u64,
+    success: bool,
+}
+
+impl Storable for StoredUsage {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        candid::encode_one(self).unwrap().into()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        candid::encode_one(self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Storable for UsageStats {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        candid::encode_one(self).unwrap().into()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        candid::encode_one(self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct StoredReservation {
+    created_at: u64,
+    expires_at: u64,
+    // klucz API, którego dzienny licznik też trzeba oddać
+    api_key: Option<[u8; 32]>,
+    // brak puli = rezerwacja sprzed pul, czyli `Chat`
+    pool: Option<QuotaPool>,
+    // kiedy wywołanie LLM ją zajęło; zajętej nie można użyć drugi raz
+    started_at: Option<u64>,
+}
+
+impl Storable for StoredReservation {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        candid::encode_one(self).unwrap().into()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        candid::encode_one(self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Storable for TierLimits {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        candid::encode_one(self).unwrap().into()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        candid::encode_one(self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+impl Storable for TierAssignment {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        candid::encode_one(self).unwrap().into()
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        candid::encode_one(self).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+/// Kto i skąd woła LLM – do dziennika zużycia.
+#[derive(Clone, Copy)]
+struct LlmCall {
+    user: Principal,
+    endpoint: &'static str,
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static USER_NAMES_STABLE: RefCell<StableBTreeMap<Principal, [u8; 32], Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)))
+        )
+    );
+
+    static USER_PROMPTS_STABLE: RefCell<StableBTreeMap<Principal, QuotaRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+        )
+    );
+
+    static USER_CHATS_STABLE: RefCell<StableBTreeMap<ChatKey, ([u8; 64], u32), Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+        )
+    );
+
+    static USER_ARCHIVE_STABLE: RefCell<StableBTreeMap<ChatKey, ([u8; 64], u32), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+        )
+    );
+
+    static CHAT_MESSAGES_STABLE: RefCell<StableBTreeMap<MessageKey, StoredMessage, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+        )
+    );
+
+    static CHAT_IMAGES_STABLE: RefCell<StableBTreeMap<MessageKey, StoredImage, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+        )
+    );
+
+    static CHAT_ACTIVE_LEAF_STABLE: RefCell<StableBTreeMap<ChatKey, u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+        )
+    );
+
+    static CHAT_CANDIDATES_STABLE: RefCell<StableBTreeMap<MessageKey, StoredCandidates, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+        )
+    );
+
+    // czat -> (czat źródłowy, ostatnia skopiowana wiadomość)
+    static CHAT_ORIGIN_STABLE: RefCell<StableBTreeMap<ChatKey, ([u8; 16], u32), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+        )
+    );
+
+    // (nazwa, liczba wiadomości, czas usunięcia)
+    static USER_TRASH_STABLE: RefCell<StableBTreeMap<ChatKey, TrashRow, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+        )
+    );
+
+    // zapisana wersja układu danych; 0 = canister sprzed wersjonowania
+    static SCHEMA_VERSION_STABLE: RefCell<StableCell<u32, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38))),
+            0,
+        )
+    );
+
+    // (etap, usunięte rekordy, start) dla kont w trakcie usuwania
+    static ACCOUNT_DELETION_STABLE: RefCell<StableBTreeMap<Principal, (u8, u64, u64), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+        )
+    );
+
+    // (użytkownik, sha256(metoda, klucz)) -> zapamiętana odpowiedź
+    static IDEMPOTENCY_STABLE: RefCell<StableBTreeMap<IdempotencyKey, StoredResponse, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+        )
+    );
+
+    // indeks po czasie do wygaszania najstarszych wpisów IDEMPOTENCY_STABLE
+    static IDEMPOTENCY_BY_TIME_STABLE: RefCell<StableBTreeMap<(u64, IdempotencyKey), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+        )
+    );
+
+    static CHAT_VERSIONS_STABLE: RefCell<StableBTreeMap<ChatKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+        )
+    );
+
+    // dziennik zmian: (użytkownik, seq) -> zmiana
+    static CHANGE_LOG_STABLE: RefCell<StableBTreeMap<(Principal, u64), StoredChange, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+        )
+    );
+
+    // ostatni nadany seq użytkownika; nie cofa się po przycięciu dziennika
+    static CHANGE_SEQ_STABLE: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+        )
+    );
+
+    // otwarte uploady: (użytkownik, id uploadu) -> sesja
+    static UPLOADS_STABLE: RefCell<StableBTreeMap<ChatKey, StoredUpload, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+        )
+    );
+
+    // fragmenty uploadów: ((użytkownik, id uploadu), numer) -> bajty
+    static UPLOAD_CHUNKS_STABLE: RefCell<StableBTreeMap<MessageKey, Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+        )
+    );
+
+    // otwarte pobierania: (użytkownik, uchwyt) -> opis treści
+    static DOWNLOADS_STABLE: RefCell<StableBTreeMap<ChatKey, StoredDownload, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+        )
+    );
+
+    // migawka treści pocięta na fragmenty: ((użytkownik, uchwyt), numer) -> bajty
+    static DOWNLOAD_CHUNKS_STABLE: RefCell<StableBTreeMap<MessageKey, Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+        )
+    );
+
+    // linki udostępniające: sha256(token) -> link
+    static SHARE_LINKS_STABLE: RefCell<StableBTreeMap<[u8; 32], StoredShareLink, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+        )
+    );
+
+    // indeks linków użytkownika: (właściciel, sha256(token)) -> ()
+    static SHARE_LINKS_BY_USER_STABLE: RefCell<StableBTreeMap<(Principal, [u8; 32]), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+        )
+    );
+
+    // klucze API: sha256(token) -> klucz
+    static API_KEYS_STABLE: RefCell<StableBTreeMap<[u8; 32], StoredApiKey, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+        )
+    );
+
+    // indeks kluczy użytkownika: (właściciel, sha256(token)) -> ()
+    static API_KEYS_BY_USER_STABLE: RefCell<StableBTreeMap<(Principal, [u8; 32]), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+        )
+    );
+
+    // liczniki: (licznik, etykieta) -> wartość
+    static COUNTERS_STABLE: RefCell<StableBTreeMap<(u8, [u8; 32]), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+        )
+    );
+
+    // zablokowani użytkownicy (przeżywają usunięcie danych konta)
+    static BANNED_STABLE: RefCell<StableBTreeMap<Principal, Ban, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+        )
+    );
+
+    // indywidualne limity promptów ustawione przez operatora
+    static PROMPT_LIMITS_STABLE: RefCell<StableBTreeMap<Principal, u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+        )
+    );
+
+    // dziennik wywołań LLM: (użytkownik, seq) -> wpis
+    static USAGE_LEDGER_STABLE: RefCell<StableBTreeMap<(Principal, u64), StoredUsage, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+        )
+    );
+
+    static USAGE_SEQ_STABLE: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+        )
+    );
+
+    // dzienne sumy: ((użytkownik, dzień), model) -> zużycie
+    static USAGE_DAILY_STABLE: RefCell<StableBTreeMap<UsageDayKey, UsageStats, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+        )
+    );
+
+    // rezerwacje promptów: (użytkownik, id) -> rezerwacja
+    static PROMPT_RESERVATIONS_STABLE: RefCell<StableBTreeMap<ChatKey, StoredReservation, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+        )
+    );
+
+    // pule poza `Chat` (ta zostaje w USER_PROMPTS_STABLE): (użytkownik, pula) -> (licznik, blokada)
+    static QUOTA_POOLS_STABLE: RefCell<StableBTreeMap<(Principal, u8), QuotaRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+        )
+    );
+
+    // limity planów zmienione przez kontrolerów (brak wpisu = domyślne)
+    static TIER_LIMITS_STABLE: RefCell<StableBTreeMap<u8, TierLimits, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))
         )
     );
 
-    static USER_CHATS_STABLE: RefCell<StableBTreeMap<(Principal, [u8; 16]), ([u8; 64], u32), Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
-        )
-    );
+    // przypisane plany (brak wpisu = `Free`)
+    static USER_TIERS_STABLE: RefCell<StableBTreeMap<Principal, TierAssignment, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34)))
+        )
+    );
+
+    // czaty w koszu, które przed usunięciem były zarchiwizowane
+    static TRASH_FROM_ARCHIVE_STABLE: RefCell<StableBTreeMap<ChatKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+        )
+    );
+
+    // czaty usunięte z kosza, których dane są jeszcze czyszczone partiami -> kiedy usunięte
+    static PURGE_QUEUE_STABLE: RefCell<StableBTreeMap<ChatKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36)))
+        )
+    );
+
+    // sha256(token) -> token właściciela do obrazów przez HTTP
+    static IMAGE_TOKENS_STABLE: RefCell<StableBTreeMap<[u8; 32], StoredImageToken, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+        )
+    );
+
+    static SETTINGS_STABLE: RefCell<StableBTreeMap<u8, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+        )
+    );
+
+    // ziarno z raw_rand, ustawiane po init/upgrade albo przy pierwszym użyciu
+    static RNG_SEED: RefCell<Option<[u8; 32]>> = const { RefCell::new(None) };
+    static RNG_COUNTER: RefCell<u64> = const { RefCell::new(0) };
+
+    // czaty, w których trwa właśnie tura LLM
+    static CHATS_IN_FLIGHT: RefCell<HashSet<(Principal, [u8; 16])>> = RefCell::new(HashSet::new());
+
+    // skróty eksportów budowanych w tle (`process_export_builds`)
+    static EXPORT_HASHERS: RefCell<HashMap<(Principal, [u8; 16]), Sha256>> = RefCell::new(HashMap::new());
+
+    // zajętość pamięci użytkownika: (bajty, kiedy policzone od zera)
+    static STORAGE_USAGE: RefCell<HashMap<Principal, (u64, u64)>> = RefCell::new(HashMap::new());
+
+    // czas ostatniego init/post_upgrade
+    static STARTED_AT: RefCell<u64> = const { RefCell::new(0) };
+
+    // wyniki ostatnich wywołań LLM od startu
+    static LLM_HEALTH: RefCell<LlmHealth> = const { RefCell::new(LlmHealth {
+        reachable: None,
+        last_attempt: None,
+        last_success: None,
+        last_failure: None,
+    }) };
+}
+
+fn update_image_content(
+    user: Principal,
+    chat_id: [u8; 16],
+    msg_id: u32,
+    new_content: &str,
+    size: Option<(u32, u32)>,
+) -> Result<(), ApiError> {
+    CHAT_IMAGES_STABLE.with(|map| {
+        let mut map = map.borrow_mut();
+        let key = ((user, chat_id), msg_id);
+
+        if let Some(stored_image) = map.get(&key) {
+            let mut stored_copy = stored_image.clone();
+            // konwersja nowego contentu do [u8; 100000]
+            /*let bytes = new_content.as_bytes();
+            if bytes.len() > 100000 {
+                return Err("New content too large for buffer (max 100000 bytes)".to_string());
+            }*/
+            stored_copy.data = string_to_bytes(new_content);
+            if let Some((width, height)) = size {
+                stored_copy.width = width;
+                stored_copy.height = height;
+            }
+
+            /*let mut buf = [0u8; 100000];
+            buf[..bytes.len()].copy_from_slice(bytes);*/
+
+            // wstawiamy z powrotem nową wartość z tym samym meta
+            map.insert(key, stored_copy);
+            record_change(user, chat_id, ChangeKind::ImageUpdated { msg_id });
+            Ok(())
+        } else {
+            Err(ApiError::NotFound("Message not found".to_string()))
+        }
+    })
+}
+
+fn set_chat_archived_stable(user: Principal, chat_id: [u8; 16], archived: bool) -> bool {
+    let moved = move_chat_archived(user, chat_id, archived);
+    if moved {
+        record_change(user, chat_id, ChangeKind::ChatArchived { archived });
+    }
+    moved
+}
+
+fn move_chat_archived(user: Principal, chat_id: [u8; 16], archived: bool) -> bool {
+    if archived {
+        // przenieś do archiwum
+        USER_CHATS_STABLE.with(|map| {
+            let mut map = map.borrow_mut();
+            if let Some((name, msg_count)) = map.remove(&(user, chat_id)) {
+                USER_ARCHIVE_STABLE.with(|archive| {
+                    archive
+                        .borrow_mut()
+                        .insert((user, chat_id), (name, msg_count));
+                });
+                true
+            } else {
+                false
+            }
+        })
+    } else {
+        // przenieś z archiwum z powrotem do normalnej mapy
+        USER_ARCHIVE_STABLE.with(|archive| {
+            let mut archive = archive.borrow_mut();
+            if let Some((name, msg_count)) = archive.remove(&(user, chat_id)) {
+                USER_CHATS_STABLE.with(|map| {
+                    map.borrow_mut().insert((user, chat_id), (name, msg_count));
+                });
+                true
+            } else {
+                false
+            }
+        })
+    }
+}
+
+fn get_setting(setting: Setting) -> u64 {
+    SETTINGS_STABLE
+        .with(|map| map.borrow().get(&setting.key()))
+        .unwrap_or_else(|| setting.default_value())
+}
+
+fn set_setting_stable(setting: Setting, value: u64) {
+    SETTINGS_STABLE.with(|map| map.borrow_mut().insert(setting.key(), value));
+}
+
+fn start_timers() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            let _ = ensure_rng_seeded().await;
+        })
+    });
+    ic_cdk_timers::set_timer_interval(PURGE_INTERVAL, || {
+        purge_expired_trash(PURGE_BATCH);
+        process_purge_queue(PURGE_BATCH);
+        prune_idempotency_keys(time());
+        expire_uploads(time(), UPLOAD_EXPIRE_BATCH);
+        expire_downloads(time(), UPLOAD_EXPIRE_BATCH);
+        expire_reservations(time(), UPLOAD_EXPIRE_BATCH);
+        prune_deletion_tombstones(time());
+        expire_share_links(time(), UPLOAD_EXPIRE_BATCH);
+        expire_image_tokens(time());
+    });
+    schedule_account_deletion();
+    schedule_export_builds();
+}
+
+fn idempotency_key(user: Principal, method: &str, opts: &Option<WriteOpts>) -> Option<(Principal, [u8; 32])> {
+    let request_key = opts.as_ref()?.request_key.as_ref()?;
+    let mut input = method.as_bytes().to_vec();
+    input.push(0);
+    input.extend_from_slice(request_key.as_bytes());
+    Some((user, sha256(&input)))
+}
+
+/// Wynik, którym metoda odpowiada na odrzucone ponowienie (klucz w użyciu albo z innymi argumentami).
+trait Rejected {
+    fn rejected(error: ApiError) -> Self;
+}
+
+impl<T> Rejected for Result<T, ApiError> {
+    fn rejected(error: ApiError) -> Self {
+        Err(error)
+    }
+}
+
+impl Rejected for bool {
+    fn rejected(_error: ApiError) -> Self {
+        false
+    }
+}
+
+impl Rejected for u32 {
+    fn rejected(_error: ApiError) -> Self {
+        0
+    }
+}
+
+fn args_hash<A: CandidType>(args: &A) -> [u8; 32] {
+    sha256(&candid::encode_one(args).unwrap_or_default())
+}
+
+/// Odpowiedź dla ponowienia z tym samym kluczem, jeśli wpis nie wygasł: zapisany wynik,
+/// `Busy` dla wywołania, które jeszcze trwa, albo błąd, gdy argumenty są inne.
+fn idempotent_lookup<T: CandidType + for<'de> Deserialize<'de> + Rejected>(user: Principal, method: &str, opts: &Option<WriteOpts>, args: [u8; 32]) -> Option<T> {
+    let key = idempotency_key(user, method, opts)?;
+    let stored = IDEMPOTENCY_STABLE.with(|map| map.borrow().get(&key))?;
+    let age = time().saturating_sub(stored.created_at);
+    let window = get_setting(Setting::IdempotencyWindowSecs).saturating_mul(1_000_000_000);
+    if age > window {
+        return None;
+    }
+    if stored.args_hash.is_some_and(|hash| hash != args) {
+        return Some(T::rejected(ApiError::InvalidInput(format!("Request key was already used for {} with different arguments", method))));
+    }
+    if stored.pending == Some(true) {
+        // wywołanie przerwane trapem nie zapisze wyniku; po czasie na LLM klucz wolno użyć ponownie
+        if age > LLM_CALL_TIMEOUT_NANOS {
+            return None;
+        }
+        return Some(T::rejected(ApiError::Busy(format!("A {} call with this request key is still in progress", method))));
+    }
+    candid::decode_one(&stored.response).ok()
+}
+
+fn put_idempotency_entry(key: (Principal, [u8; 32]), entry: StoredResponse) {
+    let now = entry.created_at;
+    if let Some(old) = IDEMPOTENCY_STABLE.with(|map| map.borrow_mut().insert(key, entry)) {
+        IDEMPOTENCY_BY_TIME_STABLE.with(|index| index.borrow_mut().remove(&(old.created_at, key)));
+    }
+    IDEMPOTENCY_BY_TIME_STABLE.with(|index| index.borrow_mut().insert((now, key), ()));
+
+    // limit rozmiaru: wyrzucamy najstarsze wpisy
+    let max_entries = get_setting(Setting::IdempotencyMaxEntries);
+    while IDEMPOTENCY_STABLE.with(|map| map.borrow().len()) > max_entries {
+        let Some(((_, oldest), _)) = IDEMPOTENCY_BY_TIME_STABLE.with(|index| index.borrow_mut().pop_first()) else {
+            break;
+        };
+        IDEMPOTENCY_STABLE.with(|map| map.borrow_mut().remove(&oldest));
+    }
+}
+
+/// `idempotent_lookup` dla metod z await: bez zapisanej odpowiedzi zostawia znacznik "w toku"
+/// przed pierwszym await, więc równoległe ponowienie nie uruchomi operacji drugi raz.
+fn idempotent_begin<T: CandidType + for<'de> Deserialize<'de> + Rejected>(user: Principal, method: &str, opts: &Option<WriteOpts>, args: [u8; 32]) -> Option<T> {
+    if let Some(result) = idempotent_lookup(user, method, opts, args) {
+        return Some(result);
+    }
+    if let Some(key) = idempotency_key(user, method, opts) {
+        put_idempotency_entry(key, StoredResponse { created_at: time(), response: Vec::new(), args_hash: Some(args), pending: Some(true) });
+    }
+    None
+}
+
+fn idempotent_store<T: CandidType>(user: Principal, method: &str, opts: &Option<WriteOpts>, args: [u8; 32], result: &T) {
+    let Some(key) = idempotency_key(user, method, opts) else {
+        return;
+    };
+    let Ok(response) = candid::encode_one(result) else {
+        return;
+    };
+    put_idempotency_entry(key, StoredResponse { created_at: time(), response, args_hash: Some(args), pending: None });
+}
+
+/// Wykonuje `f` najwyżej raz dla danego klucza żądania; ponowienia dostają zapisany wynik.
+/// `args` to skrót argumentów metody bez `opts` (`args_hash`).
+fn idempotent<T, F>(user: Principal, method: &str, opts: &Option<WriteOpts>, args: [u8; 32], f: F) -> T
+where
+    T: CandidType + for<'de> Deserialize<'de> + Rejected,
+    F: FnOnce() -> T,
+{
+    if let Some(result) = idempotent_lookup(user, method, opts, args) {
+        return result;
+    }
+    let result = f();
+    idempotent_store(user, method, opts, args, &result);
+    result
+}
+
+fn prune_idempotency_keys(now: u64) {
+    let window = get_setting(Setting::IdempotencyWindowSecs).saturating_mul(1_000_000_000);
+    while let Some(((created_at, key), _)) = IDEMPOTENCY_BY_TIME_STABLE.with(|index| index.borrow().first_key_value()) {
+        if now.saturating_sub(created_at) <= window {
+            break;
+        }
+        IDEMPOTENCY_BY_TIME_STABLE.with(|index| index.borrow_mut().remove(&(created_at, key)));
+        IDEMPOTENCY_STABLE.with(|map| map.borrow_mut().remove(&key));
+    }
+}
+
+fn option_f64_to_bytes(opt: Option<u64>) -> [u8; 9] {
+    let mut bytes = [0u8; 9];
+    match opt {
+        None => bytes[0] = 0,
+        Some(val) => {
+            bytes[0] = 1;
+            bytes[1..9].copy_from_slice(&val.to_le_bytes());
+        }
+    }
+    bytes
+}
+
+fn bytes_to_option_f64(bytes: &[u8; 9]) -> Option<u64> {
+    match bytes[0] {
+        0 => None,
+        1 => Some(u64::from_le_bytes(bytes[1..9].try_into().unwrap())),
+        _ => None, // albo panic!("Invalid Option encoding")
+    }
+}
+fn string_to_bytes(s: &str) -> Vec<u8> {
+    s.as_bytes().to_vec()
+}
+fn string_to_fixed_bytes<const N: usize>(s: &str) -> [u8; N] {
+    let mut arr = [0u8; N];
+    let bytes = truncate_utf8(s, N).as_bytes();
+    arr[..bytes.len()].copy_from_slice(bytes);
+    arr
+}
+
+/// Najdłuższy prefiks `s` o długości najwyżej `max` bajtów, ucięty na granicy znaku.
+fn truncate_utf8(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Zamienia tablicę bajtów na String, ignorując trailing zera.
+/// Stare zapisy mogły uciąć znak w połowie; zwracamy wtedy poprawny prefiks.
+fn fixed_bytes_to_string(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    match std::str::from_utf8(&bytes[..len]) {
+        Ok(s) => s.to_string(),
+        Err(e) => String::from_utf8_lossy(&bytes[..e.valid_up_to()]).into_owned(),
+    }
+}
+
+fn check_length(field: &str, value: &str, max: u64) -> Result<(), ApiError> {
+    if value.len() as u64 > max {
+        return Err(ApiError::InvalidInput(format!("{} is {} bytes, limit is {}", field, value.len(), max)));
+    }
+    Ok(())
+}
+
+/// Odrzuca znaki sterujące; w treści wiadomości dozwolone są tylko \n, \r i \t.
+fn check_control_chars(field: &str, value: &str, allow_whitespace: bool) -> Result<(), ApiError> {
+    let found = value
+        .char_indices()
+        .find(|(_, c)| c.is_control() && !(allow_whitespace && matches!(c, '\n' | '\r' | '\t')));
+    if let Some((index, c)) = found {
+        return Err(ApiError::InvalidInput(format!("{} contains control character U+{:04X} at byte {}", field, c as u32, index)));
+    }
+    Ok(())
+}
+
+fn validate_name(field: &str, value: &str, limit: Setting, storage_max: usize) -> Result<(), ApiError> {
+    if value.trim().is_empty() {
+        return Err(ApiError::InvalidInput(format!("{} must not be empty", field)));
+    }
+    check_length(field, value, get_setting(limit).min(storage_max as u64))?;
+    check_control_chars(field, value, false)
+}
+
+fn validate_chat_name(name: &str) -> Result<(), ApiError> {
+    validate_name("Chat name", name, Setting::MaxChatNameBytes, 64)
+}
+
+/// Tekst roli zapisywany w 32 bajtach (nazwa roli albo tag modelu).
+fn validate_role_text(role: &str) -> Result<(), ApiError> {
+    check_length("Role", role, 32)?;
+    check_control_chars("Role", role, false)
+}
+
+fn validate_text(field: &str, value: &str) -> Result<(), ApiError> {
+    check_length(field, value, get_setting(Setting::MaxMessageBytes))?;
+    check_control_chars(field, value, true)
+}
+
+fn validate_image(width: u32, height: u32, data: &str) -> Result<(), ApiError> {
+    let max_side = get_setting(Setting::MaxImageSide);
+    if width == 0 || height == 0 || width as u64 > max_side || height as u64 > max_side {
+        return Err(ApiError::InvalidInput(format!("Image size {}x{} is outside 1..={} per side", width, height, max_side)));
+    }
+    let pixels = width as u64 * height as u64;
+    let max_pixels = get_setting(Setting::MaxImagePixels);
+    if pixels > max_pixels {
+        return Err(ApiError::InvalidInput(format!("Image has {} pixels, limit is {}", pixels, max_pixels)));
+    }
+    validate_image_data(data)
+}
+
+fn validate_image_data(data: &str) -> Result<(), ApiError> {
+    check_length("Image data", data, get_setting(Setting::MaxImageDataBytes))?;
+    check_control_chars("Image data", data, false)
+}
+
+fn validate_tool_token(field: &str, value: &str) -> Result<(), ApiError> {
+    check_length(field, value, 64)?;
+    check_control_chars(field, value, false)
+}
+
+fn validate_content(content: &MessageContent) -> Result<(), ApiError> {
+    match content {
+        MessageContent::Text(text) => validate_text("Message", text),
+        MessageContent::PixelImage { width, height, data } => validate_image(*width, *height, data),
+        MessageContent::ToolCall { id, name, arguments } => {
+            validate_tool_token("Tool call id", id)?;
+            validate_tool_token("Tool name", name)?;
+            let total: usize = arguments.iter().map(|a| a.name.len() + a.value.len()).sum();
+            let max = get_setting(Setting::MaxMessageBytes);
+            if total as u64 > max {
+                return Err(ApiError::InvalidInput(format!("Tool arguments are {} bytes, limit is {}", total, max)));
+            }
+            for argument in arguments {
+                validate_tool_token("Tool argument name", &argument.name)?;
+                check_control_chars("Tool argument", &argument.value, true)?;
+            }
+            Ok(())
+        }
+        MessageContent::ToolResult { call_id, content } => {
+            validate_tool_token("Tool call id", call_id)?;
+            validate_text("Tool result", content)
+        }
+    }
+}
+
+fn add_counter(counter: Counter, label: &str, delta: i64) {
+    let key = (counter.key(), string_to_fixed_bytes::<32>(label));
+    COUNTERS_STABLE.with(|map| {
+        let mut map = map.borrow_mut();
+        let value = map.get(&key).unwrap_or(0).saturating_add_signed(delta);
+        map.insert(key, value);
+    });
+}
+
+fn counters(counter: Counter) -> Vec<(String, u64)> {
+    let key = counter.key();
+    COUNTERS_STABLE.with(|map| {
+        map.borrow()
+            .range((key, [0u8; 32])..=(key, [u8::MAX; 32]))
+            .map(|entry| (fixed_bytes_to_string(&entry.key().1), entry.value()))
+            .collect()
+    })
+}
+
+fn prompt_limit(user: Principal) -> u32 {
+    quota_limit(user, QuotaPool::Chat)
+}
+
+/// Limit puli z planu użytkownika; dla `Chat` operator może ustawić limit indywidualny.
+fn quota_limit(user: Principal, pool: QuotaPool) -> u32 {
+    let default = user_tier_limits(user).quota(pool);
+    match pool {
+        QuotaPool::Chat => PROMPT_LIMITS_STABLE.with(|map| map.borrow().get(&user)).unwrap_or(default),
+        _ => default,
+    }
+}
+
+/// Domyślne limity; pule `Free` biorą się z ustawień `*QuotaLimit`.
+fn default_tier_limits(tier: Tier) -> TierLimits {
+    let base = |pool: QuotaPool| get_setting(pool.limit_setting()).min(u32::MAX as u64) as u32;
+    // Free zachowuje wszystko, co aplikacja dawała przed planami; płatne plany dokładają limity
+    let models = [Model::Llama3_1_8B, Model::Qwen3_32B, Model::Llama4Scout];
+    let max_image_side = get_setting(Setting::MaxImageSide).min(u32::MAX as u64) as u32;
+    let (multiplier, max_chats, max_storage_bytes) = match tier {
+        Tier::Free => (1, 10_000, 256 * MIB),
+        Tier::Pro => (4, 50_000, 1024 * MIB),
+        Tier::Team => (10, 100_000, 4096 * MIB),
+    };
+    TierLimits {
+        chat_quota: base(QuotaPool::Chat).saturating_mul(multiplier),
+        drawing_quota: base(QuotaPool::Drawing).saturating_mul(multiplier),
+        tool_quota: base(QuotaPool::Tool).saturating_mul(multiplier),
+        models: models.iter().map(|m| m.to_string()).collect(),
+        max_chats,
+        max_storage_bytes,
+        max_image_side,
+    }
+}
+
+fn tier_limits(tier: Tier) -> TierLimits {
+    TIER_LIMITS_STABLE.with(|map| map.borrow().get(&tier.key())).unwrap_or_else(|| default_tier_limits(tier))
+}
+
+/// Aktywne przypisanie planu; wygasłe traktujemy jak brak.
+fn tier_assignment(user: Principal) -> Option<TierAssignment> {
+    USER_TIERS_STABLE.with(|map| map.borrow().get(&user))
+        .filter(|assignment| assignment.expires_at.is_none_or(|expires_at| time() < expires_at))
+}
+
+fn user_tier(user: Principal) -> Tier {
+    tier_assignment(user).map_or(Tier::Free, |assignment| assignment.tier)
+}
+
+fn user_tier_limits(user: Principal) -> TierLimits {
+    tier_limits(user_tier(user))
+}
+
+fn check_model_allowed(user: Principal, model: &str) -> Result<(), ApiError> {
+    if user_tier_limits(user).models.iter().any(|m| m == model) {
+        return Ok(());
+    }
+    Err(ApiError::ModelNotAllowed(format!("Model {} is not available on the {:?} tier", model, user_tier(user))))
+}
+
+fn check_chat_count(user: Principal) -> Result<(), ApiError> {
+    let range = (user, [0u8; 16])..=(user, [u8::MAX; 16]);
+    let chats = USER_CHATS_STABLE.with(|map| map.borrow().keys_range(range.clone()).count())
+        + USER_ARCHIVE_STABLE.with(|map| map.borrow().keys_range(range).count());
+    let max = user_tier_limits(user).max_chats;
+    if chats as u64 >= max as u64 {
+        return Err(ApiError::InvalidInput(format!("Chat limit of {} reached", max)));
+    }
+    Ok(())
+}
+
+fn check_tier_image(user: Principal, width: u32, height: u32) -> Result<(), ApiError> {
+    let max_side = user_tier_limits(user).max_image_side;
+    if width > max_side || height > max_side {
+        return Err(ApiError::InvalidInput(format!("Images on the {:?} tier are limited to {} per side", user_tier(user), max_side)));
+    }
+    Ok(())
+}
+
+/// Zakodowany rozmiar wiadomości i obrazów użytkownika w bajtach (pełny przegląd, tylko w update).
+fn user_storage(user: Principal) -> u64 {
+    let entry_range = ((user, [0u8; 16]), 0)..=((user, [u8::MAX; 16]), u32::MAX);
+    let message_bytes: u64 = CHAT_MESSAGES_STABLE.with(|map| {
+        map.borrow().range(entry_range.clone()).map(|entry| entry.value().to_bytes().len() as u64).sum()
+    });
+    let image_bytes: u64 = CHAT_IMAGES_STABLE.with(|map| {
+        map.borrow().range(entry_range).map(|entry| entry.value().to_bytes().len() as u64).sum()
+    });
+    message_bytes + image_bytes
+}
+
+/// Sprawdza limit pamięci planu przed zapisem `extra` bajtów. Zajętość jest przybliżona:
+/// liczona od zera co `STORAGE_RECOUNT_NANOS`, a pomiędzy tylko powiększana o zapisy.
+fn check_storage(user: Principal, extra: u64) -> Result<(), ApiError> {
+    let now = time();
+    let cached = STORAGE_USAGE.with(|cache| cache.borrow().get(&user).copied())
+        .filter(|(_, counted_at)| now.saturating_sub(*counted_at) < STORAGE_RECOUNT_NANOS);
+    let (used, counted_at) = cached.unwrap_or_else(|| (user_storage(user), now));
+    let max = user_tier_limits(user).max_storage_bytes;
+    if used.saturating_add(extra) > max {
+        return Err(ApiError::InvalidInput(format!("Storage limit of {} bytes reached", max)));
+    }
+    STORAGE_USAGE.with(|cache| cache.borrow_mut().insert(user, (used + extra, counted_at)));
+    Ok(())
+}
+
+fn content_bytes(content: &MessageContent) -> u64 {
+    (match content {
+        MessageContent::Text(text) => text.len(),
+        MessageContent::PixelImage { data, .. } => data.len(),
+        MessageContent::ToolCall { arguments, .. } => arguments.iter().map(|a| a.name.len() + a.value.len()).sum(),
+        MessageContent::ToolResult { content, .. } => content.len(),
+    }) as u64
+}
+
+fn assign_tier_stable(user: Principal, tier: Tier, source: TierSource, expires_at: Option<u64>) -> TierAssignment {
+    let assignment = TierAssignment { tier, source, assigned_at: time(), expires_at };
+    USER_TIERS_STABLE.with(|map| map.borrow_mut().insert(user, assignment.clone()));
+    assignment
+}
+
+/// Płatność cyklami dołączonymi do wywołania; ten sam aktywny plan jest przedłużany.
+/// Niższego planu nie da się kupić, dopóki trwa wyższy (opłacony czas by przepadł).
+fn buy_tier_stable(user: Principal, tier: Tier) -> Result<TierAssignment, ApiError> {
+    let price = tier.price_setting()
+        .map(get_setting)
+        .ok_or_else(|| ApiError::InvalidInput("The Free tier cannot be bought".to_string()))?;
+    let current = tier_assignment(user);
+    if current.as_ref().is_some_and(|c| c.tier == tier && c.expires_at.is_none()) {
+        return Err(ApiError::InvalidInput(format!("The {:?} tier is already assigned without expiry", tier)));
+    }
+    // klucze planów rosną razem z planem
+    if let Some(higher) = current.as_ref().filter(|c| c.tier.key() > tier.key()) {
+        return Err(ApiError::InvalidInput(format!("The {:?} tier is active until it expires; buy it again to extend", higher.tier)));
+    }
+    let available = ic_cdk::api::call::msg_cycles_available128();
+    if available < price as u128 {
+        return Err(ApiError::InvalidInput(format!("The {:?} tier costs {} cycles, {} attached", tier, price, available)));
+    }
+    ic_cdk::api::call::msg_cycles_accept128(price as u128);
+    let period = get_setting(Setting::TierPeriodSecs).saturating_mul(1_000_000_000);
+    let now = time();
+    let start = match current {
+        Some(c) if c.tier == tier => c.expires_at.unwrap_or(now),
+        // przejście na wyższy plan: pozostały opłacony czas niższego przeliczamy po cenie
+        Some(TierAssignment { tier: lower, expires_at: Some(expires_at), source: TierSource::Payment, .. }) => {
+            let lower_price = lower.price_setting().map(get_setting).unwrap_or(0);
+            let remaining = expires_at.saturating_sub(now) as u128 * lower_price as u128 / price.max(1) as u128;
+            now.saturating_add(remaining.min(u64::MAX as u128) as u64)
+        }
+        _ => now,
+    };
+    Ok(assign_tier_stable(user, tier, TierSource::Payment, Some(start.saturating_add(period))))
+}
+
+fn validate_tier_limits(limits: &TierLimits) -> Result<(), ApiError> {
+    let known: Vec<String> = [Model::Llama3_1_8B, Model::Qwen3_32B, Model::Llama4Scout].iter().map(|m| m.to_string()).collect();
+    if let Some(model) = limits.models.iter().find(|m| !known.contains(m)) {
+        return Err(ApiError::InvalidInput(format!("Unknown model {}", model)));
+    }
+    if limits.max_image_side == 0 {
+        return Err(ApiError::InvalidInput("max_image_side must be positive".to_string()));
+    }
+    Ok(())
+}
+
+fn quota_window_nanos(pool: QuotaPool) -> u64 {
+    get_setting(pool.window_setting()).saturating_mul(1_000_000_000)
+}
+
+fn quota_record(user: Principal, pool: QuotaPool) -> Option<(u32, [u8; 9])> {
+    match pool {
+        QuotaPool::Chat => USER_PROMPTS_STABLE.with(|map| map.borrow().get(&user)),
+        _ => QUOTA_POOLS_STABLE.with(|map| map.borrow().get(&(user, pool.key()))),
+    }
+}
+
+fn set_quota_record(user: Principal, pool: QuotaPool, record: (u32, [u8; 9])) {
+    match pool {
+        QuotaPool::Chat => USER_PROMPTS_STABLE.with(|map| map.borrow_mut().insert(user, record)),
+        _ => QUOTA_POOLS_STABLE.with(|map| map.borrow_mut().insert((user, pool.key()), record)),
+    };
+}
+
+fn is_banned(user: Principal) -> bool {
+    BANNED_STABLE.with(|map| map.borrow().contains_key(&user))
+}
+
+/// Guard wszystkich metod poza operatorskimi: zablokowany wywołujący dostaje odmowę.
+fn not_banned() -> Result<(), String> {
+    if is_banned(ic_cdk::caller()) {
+        return Err("Caller is banned".to_string());
+    }
+    Ok(())
+}
+
+fn inc_user_prompt_stable(user: Principal) -> bool {
+    inc_quota_stable(user, QuotaPool::Chat)
+}
+
+fn inc_quota_stable(user: Principal, pool: QuotaPool) -> bool {
+    let now = time();
+
+    // pobierz aktualną wartość lub ustaw domyślną
+    let mut count = 0;
+    let mut blocked_since: Option<u64> = None;
+
+    if let Some((c, b)) = quota_record(user, pool) {
+        count = c;
+        blocked_since = bytes_to_option_f64(&b);
+    }
+
+    // logika blokady
+    let allowed = if let Some(block_time) = blocked_since {
+        if now - block_time >= quota_window_nanos(pool) {
+            // reset
+            set_quota_record(user, pool, (1, option_f64_to_bytes(None)));
+            true
+        } else {
+            false
+        }
+    } else {
+        count += 1;
+        if count >= quota_limit(user, pool) {
+            blocked_since = Some(now);
+        }
+        set_quota_record(user, pool, (count, option_f64_to_bytes(blocked_since)));
+        true
+    };
+    if !allowed {
+        add_counter(Counter::QuotaBlocks, pool.name(), 1);
+    }
+    allowed
+}
+
+fn quota_status(user: Principal) -> Vec<QuotaStatus> {
+    let reservations: Vec<QuotaPool> = PROMPT_RESERVATIONS_STABLE.with(|map| {
+        map.borrow()
+            .range(user_chat_range(user))
+            .map(|entry| entry.value().pool.unwrap_or(QuotaPool::Chat))
+            .collect()
+    });
+    QuotaPool::ALL
+        .iter()
+        .map(|&pool| {
+            let (used, blocked) = quota_record(user, pool).unwrap_or((0, option_f64_to_bytes(None)));
+            let window = quota_window_nanos(pool);
+            QuotaStatus {
+                pool,
+                used,
+                limit: quota_limit(user, pool),
+                window_secs: window / 1_000_000_000,
+                reserved: reservations.iter().filter(|p| **p == pool).count() as u32,
+                blocked_until: bytes_to_option_f64(&blocked).map(|since| since.saturating_add(window)),
+            }
+        })
+        .collect()
+}
+
+fn set_name_stable(principal: Principal, value: String) {
+    USER_NAMES_STABLE.with(|map| map.borrow_mut().insert(principal, string_to_fixed_bytes::<32>(&value)));
+}
+
+fn get_name_stable(principal: Principal) -> Option<[u8; 32]> {
+    USER_NAMES_STABLE.with(|map| map.borrow().get(&principal))
+}
+
+fn get_image(key: ((Principal, [u8; 16]), u32)) -> Option<String> {
+    let image = CHAT_IMAGES_STABLE.with(|image_map| {
+        let image_map = image_map.borrow();
+            image_map.get(&key)
+    });
+
+    let ext_val = if let Some(stored_image) = image {
+        Some(fixed_bytes_to_string(&stored_image.data))
+    } else { 
+        None
+    };
+
+    ext_val
+}
+
+fn get_chats_for_user(user: Principal) -> Vec<ChatMeta> {
+    USER_CHATS_STABLE.with(|map_ref| {
+        let map = map_ref.borrow();
+        map.iter()
+            .filter_map(|entry| {
+                let (p, index) = entry.key();
+                let (name, msg_count) = entry.value();
+                if *p == user {
+                    Some(ChatMeta {name: fixed_bytes_to_string(&name), id: index.clone(), msg_len: msg_count, forked_from: get_chat_origin(user, *index), version: chat_version(user, *index) })
+                } else {
+                    None
+                }
+            })
+            .collect()
+    })
+}
+
+fn get_archives_for_user(user: Principal) -> Vec<ChatMeta> {
+    USER_ARCHIVE_STABLE.with(|map_ref| {
+        let map = map_ref.borrow();
+        map.iter()
+            .filter_map(|entry| {
+                let (p, index) = entry.key();
+                let (name, msg_count) = entry.value();
+                if *p == user {
+                    Some(ChatMeta {name: fixed_bytes_to_string(&name), id: index.clone(), msg_len: msg_count, forked_from: get_chat_origin(user, *index), version: chat_version(user, *index) })
+                } else {
+                    None
+                }
+            })
+            .collect()
+    })
+}
+
+fn get_all_images_for_user(user: Principal) -> ChatInfo {
+    let mut info = ChatInfo { messages: Vec::new() };
+
+    USER_CHATS_STABLE.with(|chat_map_ref| {
+        let chat_map = chat_map_ref.borrow();
+
+        for entry in chat_map.iter() {
+            let (p, chat_id) = entry.key();
+            let (_name, msg_count) = entry.value();
+
+            if *p != user {
+                continue;
+            }
+
+            // przechodzimy po wszystkich wiadomościach w czacie
+            for msg_id in 0..msg_count {
+                let key = ((user, chat_id.clone()), msg_id);
+
+                CHAT_IMAGES_STABLE.with(|image_map_ref| {
+                    let image_map = image_map_ref.borrow();
+
+                    if let Some(stored_image) = image_map.get(&key) {
+                        // budujemy ChatMessageIC dla obrazu
+                        let msg = ChatMessageIC {
+                            role: "image".to_string(), // możesz tu wstawić np. "assistant" jeśli chcesz
+                            content: fixed_bytes_to_string(&stored_image.data),
+                            etc: (0, stored_image.width, stored_image.height), // timestamp = 0, szer./wys. z obrazu
+                            candidates: Vec::new(),
+                            selected: None,
+                        };
+                        info.messages.push(msg);
+                    }
+                });
+            }
+        }
+    });
+
+    info
+}
+
+fn chat_msg_range(user: Principal, chat_id: [u8; 16]) -> std::ops::RangeInclusive<((Principal, [u8; 16]), u32)> {
+    ((user, chat_id), 0)..=((user, chat_id), u32::MAX)
+}
+
+/// Rodzic wiadomości w drzewie; stare zapisy (bez `parent`) wiszą na poprzedniej wiadomości.
+fn message_parent(msg_id: u32, stored_message: &StoredMessage) -> Option<u32> {
+    match stored_message.parent {
+        Some(NO_PARENT) => None,
+        Some(parent) => Some(parent),
+        None => msg_id.checked_sub(1),
+    }
+}
+
+/// Rola i rodzaj treści wiadomości; dla starych zapisów wyprowadzane z tekstu roli i flagi `image`.
+fn stored_kind(stored_message: &StoredMessage) -> StoredKind {
+    if let Some(kind) = &stored_message.kind {
+        return kind.clone();
+    }
+    legacy_kind(fixed_bytes_to_string(&stored_message.role), stored_message.image)
+}
+
+/// Rodzaj wiadomości zapisanej starym API, odczytany z tekstu roli.
+fn legacy_kind(role_text: String, image: bool) -> StoredKind {
+    let role = if image {
+        MessageRole::Image
+    } else {
+        match role_text.as_str() {
+            "user" => MessageRole::User,
+            "system" => MessageRole::System,
+            "tool" => MessageRole::Tool,
+            _ => MessageRole::Assistant,
+        }
+    };
+    // stare odpowiedzi i obrazy mają w roli tag modelu
+    let model = (role_text != "user" && role_text != role.name()).then_some(role_text);
+    let content = if image { StoredContent::PixelImage } else { StoredContent::Text };
+    StoredKind { role, model, content }
+}
+
+fn typed_message(key: &((Principal, [u8; 16]), u32), stored_message: &StoredMessage) -> Option<TypedMessage> {
+    let kind = stored_kind(stored_message);
+    let text = fixed_bytes_to_string(&stored_message.data);
+    let content = match kind.content {
+        StoredContent::Text => MessageContent::Text(text),
+        StoredContent::PixelImage => {
+            let image = CHAT_IMAGES_STABLE.with(|image_map| image_map.borrow().get(key))?;
+            MessageContent::PixelImage { width: image.width, height: image.height, data: fixed_bytes_to_string(&image.data) }
+        }
+        StoredContent::ToolCall { id, name, arguments } => MessageContent::ToolCall { id, name, arguments },
+        StoredContent::ToolResult { call_id } => MessageContent::ToolResult { call_id, content: text },
+    };
+    let stored_candidates = CHAT_CANDIDATES_STABLE.with(|map| map.borrow().get(key));
+    let candidates = stored_candidates
+        .as_ref()
+        .map(|c| c.candidates.iter().map(|candidate| ChatCandidate {
+            role: fixed_bytes_to_string(&candidate.role),
+            content: fixed_bytes_to_string(&candidate.data),
+            timestamp: candidate.timestamp,
+        }).collect())
+        .unwrap_or_default();
+    Some(TypedMessage {
+        id: key.1,
+        parent: message_parent(key.1, stored_message),
+        role: kind.role,
+        content,
+        model: kind.model,
+        timestamp: stored_message.timestamp,
+        candidates,
+        selected: stored_candidates.map(|c| c.selected),
+    })
+}
+
+/// Widok zgodny ze starymi klientami: rola jako tekst, a (czas, szerokość, wysokość) w `etc`.
+fn stored_to_chat_message(key: &((Principal, [u8; 16]), u32), stored_message: &StoredMessage) -> Option<ChatMessageIC> {
+    let typed = typed_message(key, stored_message)?;
+    let (content, width, height) = match typed.content {
+        MessageContent::Text(text) => (text, 0, 0),
+        MessageContent::PixelImage { width, height, data } => (data, width, height),
+        MessageContent::ToolCall { name, arguments, .. } => {
+            let arguments: Vec<String> = arguments.iter().map(|a| format!("{}={}", a.name, a.value)).collect();
+            (format!("{}({})", name, arguments.join(", ")), 0, 0)
+        }
+        MessageContent::ToolResult { content, .. } => (content, 0, 0),
+    };
+    Some(ChatMessageIC {
+        role: fixed_bytes_to_string(&stored_message.role),
+        content,
+        etc: (typed.timestamp, width, height),
+        candidates: typed.candidates,
+        selected: typed.selected,
+    })
+}
+
+fn get_active_leaf(user: Principal, chat_id: [u8; 16], msg_count: u32) -> Option<u32> {
+    CHAT_ACTIVE_LEAF_STABLE
+        .with(|map| map.borrow().get(&(user, chat_id)))
+        .or_else(|| msg_count.checked_sub(1))
+}
+
+/// Dzieci każdego węzła czatu, zebrane jednym przejściem po wiadomościach.
+fn children_index(user: Principal, chat_id: [u8; 16]) -> HashMap<Option<u32>, Vec<u32>> {
+    CHAT_MESSAGES_STABLE.with(|map_ref| {
+        let mut children: HashMap<Option<u32>, Vec<u32>> = HashMap::new();
+        for entry in map_ref.borrow().range(chat_msg_range(user, chat_id)) {
+            let (_, idx) = entry.key();
+            children.entry(message_parent(*idx, &entry.value())).or_default().push(*idx);
+        }
+        children
+    })
+}
+
+/// Identyfikatory wiadomości od korzenia do `leaf` (włącznie).
+fn get_path_ids(user: Principal, chat_id: [u8; 16], leaf: u32) -> Vec<u32> {
+    CHAT_MESSAGES_STABLE.with(|map_ref| {
+        let map = map_ref.borrow();
+        let mut path = Vec::new();
+        let mut current = Some(leaf);
+        while let Some(idx) = current {
+            let Some(stored_message) = map.get(&((user, chat_id), idx)) else {
+                break;
+            };
+            path.push(idx);
+            current = message_parent(idx, &stored_message);
+        }
+        path.reverse();
+        path
+    })
+}
+
+/// Schodzi od `msg_id` zawsze do najnowszego dziecka, aż do liścia.
+fn latest_descendant(user: Principal, chat_id: [u8; 16], msg_id: u32) -> u32 {
+    let children = children_index(user, chat_id);
+    let mut current = msg_id;
+    while let Some(&child) = children.get(&Some(current)).and_then(|ids| ids.iter().max()) {
+        current = child;
+    }
+    current
+}
+
+fn get_msgs_for_user(user: Principal, chat_id: [u8; 16], msg_count: u32) -> ChatInfo {
+    let mut info = ChatInfo { messages: Vec::new() };
+    let Some(leaf) = get_active_leaf(user, chat_id, msg_count) else {
+        return info;
+    };
+
+    CHAT_MESSAGES_STABLE.with(|map_ref| {
+        let map = map_ref.borrow();
+        for i in get_path_ids(user, chat_id, leaf) {
+            if i >= msg_count {
+                continue;
+            }
+            let key = ((user, chat_id), i);
+            if let Some(stored_message) = map.get(&key) {
+                if let Some(message) = stored_to_chat_message(&key, &stored_message) {
+                    info.messages.push(message);
+                }
+            }
+        }
+    });
+
+    info
+}
+
+fn get_typed_messages_stable(user: Principal, chat_id: [u8; 16]) -> Vec<TypedMessage> {
+    let Some((_name, msg_count)) = USER_CHATS_STABLE.with(|map| map.borrow().get(&(user, chat_id))) else {
+        return Vec::new();
+    };
+    let Some(leaf) = get_active_leaf(user, chat_id, msg_count) else {
+        return Vec::new();
+    };
+    get_path_ids(user, chat_id, leaf)
+        .into_iter()
+        .filter_map(|id| {
+            let key = ((user, chat_id), id);
+            let stored_message = CHAT_MESSAGES_STABLE.with(|map| map.borrow().get(&key))?;
+            typed_message(&key, &stored_message)
+        })
+        .collect()
+}
+
+fn get_active_path_stable(user: Principal, chat_id: [u8; 16]) -> Option<ChatPath> {
+    let (_name, msg_count) = USER_CHATS_STABLE.with(|map| map.borrow().get(&(user, chat_id)))?;
+    let leaf = get_active_leaf(user, chat_id, msg_count);
+    let mut nodes = Vec::new();
+
+    if let Some(leaf) = leaf {
+        let children = children_index(user, chat_id);
+        for id in get_path_ids(user, chat_id, leaf) {
+            let key = ((user, chat_id), id);
+            let Some(stored_message) = CHAT_MESSAGES_STABLE.with(|map| map.borrow().get(&key)) else {
+                continue;
+            };
+            let parent = message_parent(id, &stored_message);
+            if let Some(message) = stored_to_chat_message(&key, &stored_message) {
+                nodes.push(ChatNode { id, parent, siblings: children.get(&parent).cloned().unwrap_or_default(), message });
+            }
+        }
+    }
+
+    Some(ChatPath { leaf, nodes, version: chat_version(user, chat_id) })
+}
+
+fn chat_exists(user: Principal, chat_id: [u8; 16]) -> bool {
+    USER_CHATS_STABLE.with(|map| map.borrow().contains_key(&(user, chat_id)))
+        || USER_ARCHIVE_STABLE.with(|map| map.borrow().contains_key(&(user, chat_id)))
+}
+
+fn chat_id_taken(user: Principal, chat_id: [u8; 16]) -> bool {
+    chat_exists(user, chat_id)
+        || USER_TRASH_STABLE.with(|trash| trash.borrow().contains_key(&(user, chat_id)))
+        || PURGE_QUEUE_STABLE.with(|queue| queue.borrow().contains_key(&(user, chat_id)))
+}
+
+/// Zakłada czat pod podanym id; odmawia, jeśli klucz jest już zajęty (także w archiwum i koszu).
+fn create_new_chat_stable(user: Principal, chat_id: [u8; 16], name: String) -> bool {
+    if chat_id_taken(user, chat_id) {
+        return false;
+    }
+    USER_CHATS_STABLE.with(|map| {
+        map.borrow_mut().insert((user, chat_id), (string_to_fixed_bytes::<64>(&name), 0));
+    });
+    record_change(user, chat_id, ChangeKind::ChatCreated { name, forked_from: None });
+    true
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+async fn ensure_rng_seeded() -> Result<(), ApiError> {
+    if RNG_SEED.with(|seed| seed.borrow().is_some()) {
+        return Ok(());
+    }
+    let (bytes,) = raw_rand()
+        .await
+        .map_err(|(_, msg)| ApiError::Internal(format!("raw_rand failed: {}", msg)))?;
+    let seed = sha256(&bytes);
+    RNG_SEED.with(|s| {
+        s.borrow_mut().get_or_insert(seed);
+    });
+    Ok(())
+}
+
+/// Kolejne 32 losowe bajty: sha256(ziarno || licznik).
+fn random_bytes() -> Option<[u8; 32]> {
+    let seed = RNG_SEED.with(|seed| *seed.borrow())?;
+    let counter = RNG_COUNTER.with(|c| {
+        let mut c = c.borrow_mut();
+        *c += 1;
+        *c
+    });
+    let mut input = seed.to_vec();
+    input.extend_from_slice(&counter.to_le_bytes());
+    Some(sha256(&input))
+}
+
+/// Nowe losowe 16-bajtowe id, dla którego `taken` zwraca false.
+async fn new_random_id(taken: impl Fn([u8; 16]) -> bool) -> Result<[u8; 16], ApiError> {
+    ensure_rng_seeded().await?;
+    loop {
+        let bytes = random_bytes().ok_or_else(|| ApiError::Internal("Random generator not seeded".to_string()))?;
+        let mut id = [0u8; 16];
+        id.copy_from_slice(&bytes[..16]);
+        if !taken(id) {
+            return Ok(id);
+        }
+    }
+}
+
+async fn new_upload_id(user: Principal) -> Result<[u8; 16], ApiError> {
+    new_random_id(|id| UPLOADS_STABLE.with(|map| map.borrow().contains_key(&(user, id)))).await
+}
+
+async fn new_download_handle(user: Principal) -> Result<[u8; 16], ApiError> {
+    new_random_id(|id| DOWNLOADS_STABLE.with(|map| map.borrow().contains_key(&(user, id)))).await
+}
+
+async fn new_chat_id(user: Principal) -> Result<[u8; 16], ApiError> {
+    new_random_id(|id| chat_id_taken(user, id)).await
+}
+
+fn chat_version(user: Principal, chat_id: [u8; 16]) -> u64 {
+    CHAT_VERSIONS_STABLE.with(|map| map.borrow().get(&(user, chat_id))).unwrap_or(0)
+}
+
+fn bump_chat_version(user: Principal, chat_id: [u8; 16]) -> u64 {
+    CHAT_VERSIONS_STABLE.with(|map| {
+        let mut map = map.borrow_mut();
+        let version = map.get(&(user, chat_id)).unwrap_or(0) + 1;
+        map.insert((user, chat_id), version);
+        version
+    })
+}
+
+fn check_chat_version(user: Principal, chat_id: [u8; 16], opts: &Option<WriteOpts>) -> Result<(), ApiError> {
+    let Some(expected) = opts.as_ref().and_then(|o| o.expected_version) else {
+        return Ok(());
+    };
+    let current = chat_version(user, chat_id);
+    if expected != current {
+        return Err(ApiError::Conflict { expected, current });
+    }
+    Ok(())
+}
+
+/// Zmiana w obrębie czatu: sprawdza `expected_version`, a po udanym zapisie podbija wersję.
+fn with_chat_version<T>(user: Principal, chat_id: [u8; 16], opts: &Option<WriteOpts>, f: impl FnOnce() -> Result<T, ApiError>) -> Result<T, ApiError> {
+    check_chat_version(user, chat_id, opts)?;
+    let result = f()?;
+    bump_chat_version(user, chat_id);
+    Ok(result)
+}
+
+/// Blokada tury LLM na czacie; zwalniana przy dropie (także gdy callback po await zostanie przerwany).
+struct ChatTurnLock {
+    key: (Principal, [u8; 16]),
+}
+
+impl ChatTurnLock {
+    fn acquire(user: Principal, chat_id: [u8; 16]) -> Result<ChatTurnLock, ApiError> {
+        let key = (user, chat_id);
+        if CHATS_IN_FLIGHT.with(|set| set.borrow_mut().insert(key)) {
+            Ok(ChatTurnLock { key })
+        } else {
+            Err(ApiError::Busy("Another reply is being generated in this chat".to_string()))
+        }
+    }
+}
+
+impl Drop for ChatTurnLock {
+    fn drop(&mut self) {
+        CHATS_IN_FLIGHT.with(|set| set.borrow_mut().remove(&self.key));
+    }
+}
+
+/// Dopisuje zmianę do dziennika użytkownika i przycina go do `ChangeLogMaxEntries` najnowszych wpisów.
+fn record_change(user: Principal, chat_id: [u8; 16], kind: ChangeKind) -> u64 {
+    let seq = CHANGE_SEQ_STABLE.with(|map| {
+        let mut map = map.borrow_mut();
+        let seq = map.get(&user).unwrap_or(0) + 1;
+        map.insert(user, seq);
+        seq
+    });
+    let oldest_kept = (seq + 1).saturating_sub(get_setting(Setting::ChangeLogMaxEntries).max(1));
+    CHANGE_LOG_STABLE.with(|map| {
+        let mut map = map.borrow_mut();
+        map.insert((user, seq), StoredChange { timestamp: time(), chat_id, kind });
+        remove_range_batch(&mut map, (user, 0)..=(user, oldest_kept.saturating_sub(1)), usize::MAX);
+    });
+    seq
+}
+
+fn last_change_seq(user: Principal) -> u64 {
+    CHANGE_SEQ_STABLE.with(|map| map.borrow().get(&user)).unwrap_or(0)
+}
+
+/// Zmiany po `seq` (wyłącznie), najwyżej `limit` na porcję.
+fn get_changes_since_stable(user: Principal, seq: u64, limit: u32) -> ChangeFeed {
+    let limit = limit.clamp(1, CHANGE_FEED_MAX_PAGE) as usize;
+    let last_seq = last_change_seq(user);
+    let range = (user, seq.saturating_add(1))..=(user, u64::MAX);
+    let (changes, has_more, first_kept) = CHANGE_LOG_STABLE.with(|map| {
+        let map = map.borrow();
+        let mut entries = map.range(range);
+        let changes: Vec<Change> = entries
+            .by_ref()
+            .take(limit)
+            .map(|entry| {
+                let (_, seq) = *entry.key();
+                let stored = entry.value();
+                Change { seq, timestamp: stored.timestamp, chat_id: stored.chat_id, kind: stored.kind }
+            })
+            .collect();
+        let has_more = entries.next().is_some();
+        (changes, has_more, changes_first_seq(&map, user))
+    });
+    // luka w dzienniku albo seq z przyszłości (np. po usunięciu konta)
+    let reset = seq > last_seq || (seq < last_seq && first_kept.is_none_or(|first| first > seq + 1));
+    ChangeFeed { changes: if reset { Vec::new() } else { changes }, last_seq, has_more: has_more && !reset, reset }
+}
+
+fn changes_first_seq(map: &StableBTreeMap<(Principal, u64), StoredChange, Memory>, user: Principal) -> Option<u64> {
+    map.keys_range((user, 0)..=(user, u64::MAX)).next().map(|(_, seq)| seq)
+}
+
+fn get_chat_origin(user: Principal, chat_id: [u8; 16]) -> Option<ChatOrigin> {
+    CHAT_ORIGIN_STABLE
+        .with(|map| map.borrow().get(&(user, chat_id)))
+        .map(|(chat_id, msg_id)| ChatOrigin { chat_id, msg_id })
+}
+
+/// Kopiuje ścieżkę od korzenia do `up_to_msg_id` (z obrazami i kandydatkami) do nowego czatu.
+fn fork_chat_stable(user: Principal, chat_id: [u8; 16], up_to_msg_id: u32, new_id: [u8; 16], new_name: String) -> Result<[u8; 16], ApiError> {
+    if !chat_exists(user, chat_id) {
+        return Err(ApiError::NotFound("Chat not found".to_string()));
+    }
+    if get_stored_message(user, chat_id, up_to_msg_id).is_none() {
+        return Err(ApiError::NotFound("Message not found".to_string()));
+    }
+    if chat_id_taken(user, new_id) {
+        return Err(ApiError::Internal("Chat id already in use".to_string()));
+    }
+    let path = get_path_ids(user, chat_id, up_to_msg_id);
+    // kopia zajmuje tyle, co skopiowane wiadomości i obrazy (jak w `user_storage`)
+    let copied_bytes: u64 = path
+        .iter()
+        .map(|msg_id| {
+            let key = ((user, chat_id), *msg_id);
+            CHAT_MESSAGES_STABLE.with(|map| map.borrow().get(&key)).map_or(0, |m| m.to_bytes().len() as u64)
+                + CHAT_IMAGES_STABLE.with(|map| map.borrow().get(&key)).map_or(0, |i| i.to_bytes().len() as u64)
+        })
+        .sum();
+    check_storage(user, copied_bytes)?;
+
+    for (new_index, old_index) in path.iter().enumerate() {
+        let new_index = new_index as u32;
+        let old_key = ((user, chat_id), *old_index);
+        let new_key = ((user, new_id), new_index);
+
+        let Some(mut stored_message) = CHAT_MESSAGES_STABLE.with(|map| map.borrow().get(&old_key)) else {
+            continue;
+        };
+        stored_message.parent = Some(new_index.checked_sub(1).unwrap_or(NO_PARENT));
+        CHAT_MESSAGES_STABLE.with(|map| map.borrow_mut().insert(new_key, stored_message));
+
+        if let Some(stored_image) = CHAT_IMAGES_STABLE.with(|map| map.borrow().get(&old_key)) {
+            CHAT_IMAGES_STABLE.with(|map| map.borrow_mut().insert(new_key, stored_image));
+        }
+        if let Some(stored_candidates) = CHAT_CANDIDATES_STABLE.with(|map| map.borrow().get(&old_key)) {
+            CHAT_CANDIDATES_STABLE.with(|map| map.borrow_mut().insert(new_key, stored_candidates));
+        }
+    }
+
+    let msg_count = path.len() as u32;
+    USER_CHATS_STABLE.with(|map| {
+        map.borrow_mut().insert((user, new_id), (string_to_fixed_bytes::<64>(&new_name), msg_count));
+    });
+    if let Some(leaf) = msg_count.checked_sub(1) {
+        CHAT_ACTIVE_LEAF_STABLE.with(|map| map.borrow_mut().insert((user, new_id), leaf));
+    }
+    CHAT_ORIGIN_STABLE.with(|map| map.borrow_mut().insert((user, new_id), (chat_id, up_to_msg_id)));
+    record_change(user, new_id, ChangeKind::ChatCreated { name: new_name, forked_from: get_chat_origin(user, new_id) });
+
+    Ok(new_id)
+}
+
+/// Usuwa dane czatu, najwyżej `limit` wiadomości/obrazów/kandydatów na wywołanie.
+/// Zwraca liczbę usuniętych rekordów i czy czat jest już pusty.
+fn purge_chat_data(user: Principal, chat_id: [u8; 16], limit: usize) -> (usize, bool) {
+    let mut removed = CHAT_IMAGES_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), chat_msg_range(user, chat_id), limit));
+    removed += CHAT_CANDIDATES_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), chat_msg_range(user, chat_id), limit - removed));
+    removed += CHAT_MESSAGES_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), chat_msg_range(user, chat_id), limit - removed));
+    if removed >= limit {
+        return (removed, false);
+    }
+    CHAT_ACTIVE_LEAF_STABLE.with(|map| map.borrow_mut().remove(&(user, chat_id)));
+    CHAT_ORIGIN_STABLE.with(|map| map.borrow_mut().remove(&(user, chat_id)));
+    CHAT_VERSIONS_STABLE.with(|map| map.borrow_mut().remove(&(user, chat_id)));
+    (removed, true)
+}
+
+/// Dokańcza czyszczenie czatów z kolejki, łącznie najwyżej `limit` rekordów.
+fn process_purge_queue(limit: usize) -> usize {
+    let queued: Vec<(Principal, [u8; 16])> = PURGE_QUEUE_STABLE.with(|queue| queue.borrow().keys().take(limit).collect());
+    let mut removed = 0;
+    for (user, chat_id) in queued {
+        if removed >= limit {
+            break;
+        }
+        let (count, done) = purge_chat_data(user, chat_id, limit - removed);
+        removed += count;
+        if done {
+            PURGE_QUEUE_STABLE.with(|queue| queue.borrow_mut().remove(&(user, chat_id)));
+        }
+    }
+    removed
+}
+
+/// Przenosi czat (aktywny lub zarchiwizowany) do kosza; dane znikają dopiero po purge.
+fn delete_chat_stable(user: Principal, chat_id: [u8; 16]) -> bool {
+    let removed = USER_CHATS_STABLE.with(|map| map.borrow_mut().remove(&(user, chat_id))).map(|chat| (chat, false)).or_else(|| {
+        USER_ARCHIVE_STABLE.with(|map| map.borrow_mut().remove(&(user, chat_id))).map(|chat| (chat, true))
+    });
+    if let Some(((name, msg_count), archived)) = removed {
+        USER_TRASH_STABLE.with(|trash| {
+            trash.borrow_mut().insert((user, chat_id), (name, msg_count, time()));
+        });
+        if archived {
+            TRASH_FROM_ARCHIVE_STABLE.with(|map| map.borrow_mut().insert((user, chat_id), ()));
+        }
+        record_change(user, chat_id, ChangeKind::ChatDeleted);
+        true
+    } else {
+        false
+    }
+}
+
+/// Przywraca czat z kosza tam, skąd został usunięty (lista aktywnych albo archiwum).
+fn restore_chat_stable(user: Principal, chat_id: [u8; 16]) -> bool {
+    let Some((name, msg_count, _deleted_at)) = USER_TRASH_STABLE.with(|trash| trash.borrow_mut().remove(&(user, chat_id))) else {
+        return false;
+    };
+    if TRASH_FROM_ARCHIVE_STABLE.with(|map| map.borrow_mut().remove(&(user, chat_id))).is_some() {
+        USER_ARCHIVE_STABLE.with(|map| map.borrow_mut().insert((user, chat_id), (name, msg_count)));
+    } else {
+        USER_CHATS_STABLE.with(|map| map.borrow_mut().insert((user, chat_id), (name, msg_count)));
+    }
+    record_change(user, chat_id, ChangeKind::ChatRestored);
+    true
+}
+
+fn get_trash_for_user(user: Principal) -> Vec<TrashedChat> {
+    let retention = get_setting(Setting::TrashRetentionSecs).saturating_mul(1_000_000_000);
+    USER_TRASH_STABLE.with(|map_ref| {
+        map_ref
+            .borrow()
+            .range((user, [0u8; 16])..=(user, [u8::MAX; 16]))
+            .map(|entry| {
+                let (_, chat_id) = entry.key();
+                let (name, msg_count, deleted_at) = entry.value();
+                TrashedChat {
+                    chat: ChatMeta { name: fixed_bytes_to_string(&name), id: *chat_id, msg_len: msg_count, forked_from: get_chat_origin(user, *chat_id), version: chat_version(user, *chat_id) },
+                    deleted_at,
+                    purge_at: deleted_at.saturating_add(retention),
+                }
+            })
+            .collect()
+    })
+}
+
+fn purge_chat_stable(user: Principal, chat_id: [u8; 16]) -> bool {
+    if USER_TRASH_STABLE.with(|trash| trash.borrow_mut().remove(&(user, chat_id))).is_some() {
+        TRASH_FROM_ARCHIVE_STABLE.with(|map| map.borrow_mut().remove(&(user, chat_id)));
+        // reszta danych jest dokańczana przez timer, gdy czat nie zmieści się w jednej partii
+        if !purge_chat_data(user, chat_id, PURGE_BATCH).1 {
+            PURGE_QUEUE_STABLE.with(|queue| queue.borrow_mut().insert((user, chat_id), time()));
+        }
+        record_change(user, chat_id, ChangeKind::ChatPurged);
+        true
+    } else {
+        false
+    }
+}
+
+fn purge_trash_for_user(user: Principal, chat_id: Option<[u8; 16]>) -> u32 {
+    let chat_ids: Vec<[u8; 16]> = match chat_id {
+        Some(chat_id) => vec![chat_id],
+        None => USER_TRASH_STABLE.with(|trash| {
+            trash
+                .borrow()
+                .keys_range((user, [0u8; 16])..=(user, [u8::MAX; 16]))
+                .map(|(_, chat_id)| chat_id)
+                .collect()
+        }),
+    };
+    chat_ids
+        .into_iter()
+        .filter(|chat_id| purge_chat_stable(user, *chat_id))
+        .count() as u32
+}
+
+/// Czyści z kosza czaty starsze niż okres retencji, najwyżej `limit` na wywołanie.
+fn purge_expired_trash(limit: usize) -> u32 {
+    let retention = get_setting(Setting::TrashRetentionSecs).saturating_mul(1_000_000_000);
+    let now = time();
+    let expired: Vec<(Principal, [u8; 16])> = USER_TRASH_STABLE.with(|trash| {
+        trash
+            .borrow()
+            .iter()
+            .filter_map(|entry| {
+                let (_, _, deleted_at) = entry.value();
+                if now.saturating_sub(deleted_at) >= retention {
+                    Some(*entry.key())
+                } else {
+                    None
+                }
+            })
+            .take(limit)
+            .collect()
+    });
+    expired
+        .into_iter()
+        .filter(|(user, chat_id)| purge_chat_stable(*user, *chat_id))
+        .count() as u32
+}
+
+fn user_msg_range(user: Principal) -> std::ops::RangeInclusive<((Principal, [u8; 16]), u32)> {
+    ((user, [0u8; 16]), 0)..=((user, [u8::MAX; 16]), u32::MAX)
+}
+
+fn user_chat_range(user: Principal) -> std::ops::RangeInclusive<(Principal, [u8; 16])> {
+    (user, [0u8; 16])..=(user, [u8::MAX; 16])
+}
+
+fn remove_range_batch<K, V>(map: &mut StableBTreeMap<K, V, Memory>, range: std::ops::RangeInclusive<K>, limit: usize) -> usize
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+{
+    let keys: Vec<K> = map.keys_range(range).take(limit).collect();
+    for key in &keys {
+        map.remove(key);
+    }
+    keys.len()
+}
+
+fn export_chat_record(user: Principal, chat_id: [u8; 16], name: [u8; 64], msg_count: u32, archived: bool, deleted_at: Option<u64>) -> ExportRecord {
+    ExportRecord::Chat {
+        chat: ChatMeta { name: fixed_bytes_to_string(&name), id: chat_id, msg_len: msg_count, forked_from: get_chat_origin(user, chat_id), version: chat_version(user, chat_id) },
+        archived,
+        deleted_at,
+        active_leaf: CHAT_ACTIVE_LEAF_STABLE.with(|map| map.borrow().get(&(user, chat_id))),
+    }
+}
+
+/// Jedna porcja eksportu danych użytkownika; `next` jest puste, gdy wszystko zostało zwrócone.
+fn export_user_data(user: Principal, cursor: Option<ExportCursor>, limit: u32) -> ExportChunk {
+    let limit = limit.clamp(1, EXPORT_MAX_RECORDS) as usize;
+    let mut records = Vec::new();
+    let mut store = cursor.as_ref().map(|c| c.store).unwrap_or(DataStore::Names);
+    let mut after_seq = cursor.as_ref().and_then(|c| c.seq);
+    let mut after = cursor.map(|c| (c.chat_id, c.msg_id));
+    // profil (imię + limit promptów) i plan zawsze na początku
+    if after.is_none() && store == DataStore::Names {
+        let prompts = USER_PROMPTS_STABLE.with(|map| map.borrow().get(&user));
+        records.push(ExportRecord::Profile {
+            name: get_name_stable(user).map(|b| fixed_bytes_to_string(&b)),
+            prompt_count: prompts.map(|(count, _)| count).unwrap_or(0),
+            blocked_since: prompts.and_then(|(_, blocked)| bytes_to_option_f64(&blocked)),
+        });
+        records.push(ExportRecord::Plan { assignment: tier_assignment(user), quotas: quota_status(user) });
+        store = DataStore::Chats;
+    }
+
+    let mut last_key = None;
+    while store != DataStore::Done && records.len() < limit {
+        let remaining = limit - records.len();
+        let start_chat = after.map(|(chat_id, _)| chat_id).unwrap_or([0u8; 16]);
+        let mut batch: Vec<(ExportRecord, ([u8; 16], u32))> = Vec::new();
+
+        match store {
+            DataStore::Chats | DataStore::Archive => {
+                let source = if store == DataStore::Chats { &USER_CHATS_STABLE } else { &USER_ARCHIVE_STABLE };
+                source.with(|map| {
+                    for entry in map.borrow().range((user, start_chat)..=(user, [u8::MAX; 16])) {
+                        let (_, chat_id) = entry.key();
+                        if after.is_some() && *chat_id == start_chat {
+                            continue;
+                        }
+                        let (name, msg_count) = entry.value();
+                        batch.push((export_chat_record(user, *chat_id, name, msg_count, store == DataStore::Archive, None), (*chat_id, 0)));
+                        if batch.len() >= remaining {
+                            break;
+                        }
+                    }
+                });
+            }
+            DataStore::Trash => {
+                USER_TRASH_STABLE.with(|map| {
+                    for entry in map.borrow().range((user, start_chat)..=(user, [u8::MAX; 16])) {
+                        let (_, chat_id) = entry.key();
+                        if after.is_some() && *chat_id == start_chat {
+                            continue;
+                        }
+                        let (name, msg_count, deleted_at) = entry.value();
+                        let archived = TRASH_FROM_ARCHIVE_STABLE.with(|m| m.borrow().contains_key(&(user, *chat_id)));
+                        batch.push((export_chat_record(user, *chat_id, name, msg_count, archived, Some(deleted_at)), (*chat_id, 0)));
+                        if batch.len() >= remaining {
+                            break;
+                        }
+                    }
+                });
+            }
+            DataStore::Messages => {
+                let start = after.unwrap_or(([0u8; 16], 0));
+                CHAT_MESSAGES_STABLE.with(|map| {
+                    for entry in map.borrow().range(((user, start.0), start.1)..=((user, [u8::MAX; 16]), u32::MAX)) {
+                        let key = entry.key();
+                        let ((_, chat_id), msg_id) = key;
+                        if after == Some((*chat_id, *msg_id)) {
+                            continue;
+                        }
+                        let stored_message = entry.value();
+                        let parent = message_parent(*msg_id, &stored_message);
+                        if let Some(message) = stored_to_chat_message(key, &stored_message) {
+                            batch.push((ExportRecord::Message { chat_id: *chat_id, msg_id: *msg_id, parent, message }, (*chat_id, *msg_id)));
+                        }
+                        if batch.len() >= remaining {
+                            break;
+                        }
+                    }
+                });
+            }
+            DataStore::Images => {
+                let start = after.unwrap_or(([0u8; 16], 0));
+                CHAT_IMAGES_STABLE.with(|map| {
+                    for entry in map.borrow().range(((user, start.0), start.1)..=((user, [u8::MAX; 16]), u32::MAX)) {
+                        let ((_, chat_id), msg_id) = entry.key();
+                        if after == Some((*chat_id, *msg_id)) {
+                            continue;
+                        }
+                        let stored_image = entry.value();
+                        batch.push((
+                            ExportRecord::Image { chat_id: *chat_id, msg_id: *msg_id, width: stored_image.width, height: stored_image.height, data: fixed_bytes_to_string(&stored_image.data) },
+                            (*chat_id, *msg_id),
+                        ));
+                        if batch.len() >= remaining {
+                            break;
+                        }
+                    }
+                });
+            }
+            // małe magazyny (najwyżej MAX_SHARE_LINKS / MAX_API_KEYS); kursor to liczba zwróconych rekordów
+            DataStore::ShareLinks | DataStore::ApiKeys => {
+                let skip = after.map(|(_, position)| position).unwrap_or(0);
+                let items: Vec<ExportRecord> = if store == DataStore::ShareLinks {
+                    list_share_links_for_user(user).into_iter().map(ExportRecord::ShareLink).collect()
+                } else {
+                    list_api_keys_for_user(user).into_iter().map(ExportRecord::ApiKey).collect()
+                };
+                for (position, record) in items.into_iter().enumerate().skip(skip as usize).take(remaining) {
+                    batch.push((record, ([0u8; 16], position as u32 + 1)));
+                }
+            }
+            DataStore::Usage => {
+                let start = after_seq.map_or(0, |seq| seq.saturating_add(1));
+                USAGE_LEDGER_STABLE.with(|map| {
+                    for entry in map.borrow().range((user, start)..=(user, u64::MAX)).take(remaining) {
+                        let seq = entry.key().1;
+                        let usage = entry.value();
+                        batch.push((
+                            ExportRecord::Usage(UsageEntry {
+                                seq,
+                                timestamp: usage.timestamp,
+                                model: usage.model,
+                                endpoint: usage.endpoint,
+                                prompt_bytes: usage.prompt_bytes,
+                                response_bytes: usage.response_bytes,
+                                success: usage.success,
+                            }),
+                            ([0u8; 16], 0),
+                        ));
+                        after_seq = Some(seq);
+                    }
+                });
+            }
+            // kandydatki, liście, pochodzenie i wersje są już zawarte w rekordach czatów i wiadomości;
+            // klucze żądań, dziennik zmian, uploady, pobrania i rezerwacje to stan przejściowy
+            _ => {}
+        }
+
+        let exhausted = batch.len() < remaining;
+        if let Some((_, key)) = batch.last() {
+            last_key = Some((store, *key));
+        }
+        records.extend(batch.into_iter().map(|(record, _)| record));
+
+        if exhausted {
+            store = match store {
+                DataStore::Chats => DataStore::Archive,
+                DataStore::Archive => DataStore::Trash,
+                DataStore::Trash => DataStore::Messages,
+                DataStore::Messages => DataStore::Images,
+                DataStore::Images => DataStore::ShareLinks,
+                DataStore::ShareLinks => DataStore::ApiKeys,
+                DataStore::ApiKeys => DataStore::Usage,
+                _ => DataStore::Done,
+            };
+            after = None;
+            after_seq = None;
+            last_key = None;
+        } else {
+            break;
+        }
+    }
+
+    let next = if store == DataStore::Done {
+        None
+    } else {
+        Some(match last_key {
+            Some((store, (chat_id, msg_id))) => ExportCursor { store, chat_id, msg_id, seq: after_seq },
+            // porcja zakończyła się dokładnie na granicy magazynów
+            None => ExportCursor { store, chat_id: [0u8; 16], msg_id: 0, seq: None },
+        })
+    };
+
+    ExportChunk { records, next }
+}
+
+/// Usuwa do `limit` rekordów użytkownika z jednego magazynu; zwraca liczbę usuniętych.
+fn delete_user_store_batch(user: Principal, store: DataStore, limit: usize) -> usize {
+    match store {
+        DataStore::Messages => CHAT_MESSAGES_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), user_msg_range(user), limit)),
+        DataStore::Images => CHAT_IMAGES_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), user_msg_range(user), limit)),
+        DataStore::Candidates => CHAT_CANDIDATES_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), user_msg_range(user), limit)),
+        DataStore::ActiveLeaves => CHAT_ACTIVE_LEAF_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), user_chat_range(user), limit)),
+        DataStore::Origins => CHAT_ORIGIN_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), user_chat_range(user), limit)),
+        DataStore::Versions => CHAT_VERSIONS_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), user_chat_range(user), limit)),
+        DataStore::Chats => USER_CHATS_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), user_chat_range(user), limit)),
+        DataStore::Archive => USER_ARCHIVE_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), user_chat_range(user), limit)),
+        DataStore::Trash => USER_TRASH_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), user_chat_range(user), limit)),
+        DataStore::Prompts => {
+            let pools = QuotaPool::ALL
+                .iter()
+                .filter(|pool| QUOTA_POOLS_STABLE.with(|m| m.borrow_mut().remove(&(user, pool.key()))).is_some())
+                .count();
+            pools + USER_PROMPTS_STABLE.with(|m| m.borrow_mut().remove(&user)).map_or(0, |_| 1)
+        }
+        DataStore::Names => USER_NAMES_STABLE.with(|m| m.borrow_mut().remove(&user)).map_or(0, |_| 1),
+        DataStore::RequestKeys => {
+            let entries: Vec<((Principal, [u8; 32]), u64)> = IDEMPOTENCY_STABLE.with(|m| {
+                m.borrow()
+                    .range((user, [0u8; 32])..=(user, [u8::MAX; 32]))
+                    .take(limit)
+                    .map(|entry| (*entry.key(), entry.value().created_at))
+                    .collect()
+            });
+            for (key, created_at) in &entries {
+                IDEMPOTENCY_STABLE.with(|m| m.borrow_mut().remove(key));
+                IDEMPOTENCY_BY_TIME_STABLE.with(|index| index.borrow_mut().remove(&(*created_at, *key)));
+            }
+            entries.len()
+        }
+        DataStore::Changes => {
+            let removed = CHANGE_LOG_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), (user, 0)..=(user, u64::MAX), limit));
+            if removed < limit {
+                CHANGE_SEQ_STABLE.with(|m| m.borrow_mut().remove(&user));
+            }
+            removed
+        }
+        DataStore::Uploads => {
+            let removed = UPLOAD_CHUNKS_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), user_msg_range(user), limit));
+            if removed < limit {
+                removed + UPLOADS_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), user_chat_range(user), limit - removed))
+            } else {
+                removed
+            }
+        }
+        DataStore::Downloads => {
+            let removed = DOWNLOAD_CHUNKS_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), user_msg_range(user), limit));
+            if removed < limit {
+                removed + DOWNLOADS_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), user_chat_range(user), limit - removed))
+            } else {
+                removed
+            }
+        }
+        DataStore::ShareLinks => {
+            let ids: Vec<(Principal, [u8; 32])> = SHARE_LINKS_BY_USER_STABLE.with(|m| {
+                m.borrow().keys_range((user, [0u8; 32])..=(user, [u8::MAX; 32])).take(limit).collect()
+            });
+            for key in &ids {
+                SHARE_LINKS_BY_USER_STABLE.with(|m| m.borrow_mut().remove(key));
+                SHARE_LINKS_STABLE.with(|m| m.borrow_mut().remove(&key.1));
+            }
+            ids.len()
+        }
+        DataStore::ApiKeys => {
+            let ids: Vec<(Principal, [u8; 32])> = API_KEYS_BY_USER_STABLE.with(|m| {
+                m.borrow().keys_range((user, [0u8; 32])..=(user, [u8::MAX; 32])).take(limit).collect()
+            });
+            for key in &ids {
+                API_KEYS_BY_USER_STABLE.with(|m| m.borrow_mut().remove(key));
+                API_KEYS_STABLE.with(|m| m.borrow_mut().remove(&key.1));
+            }
+            ids.len()
+        }
+        DataStore::Usage => {
+            let mut removed = USAGE_LEDGER_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), (user, 0)..=(user, u64::MAX), limit));
+            if removed < limit {
+                removed += USAGE_DAILY_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), usage_day_range(user, 0, u64::MAX), limit - removed));
+            }
+            if removed < limit {
+                USAGE_SEQ_STABLE.with(|m| m.borrow_mut().remove(&user));
+            }
+            removed
+        }
+        DataStore::Reservations => {
+            PROMPT_RESERVATIONS_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), user_chat_range(user), limit))
+        }
+        DataStore::Tier => USER_TIERS_STABLE.with(|m| m.borrow_mut().remove(&user)).map_or(0, |_| 1),
+        DataStore::TrashOrigins => TRASH_FROM_ARCHIVE_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), user_chat_range(user), limit)),
+        DataStore::PurgeQueue => PURGE_QUEUE_STABLE.with(|m| remove_range_batch(&mut m.borrow_mut(), user_chat_range(user), limit)),
+        // tokeny żyją kwadrans, więc tabela jest mała i można ją przejrzeć całą
+        DataStore::ImageTokens => {
+            let ids: Vec<[u8; 32]> = IMAGE_TOKENS_STABLE.with(|m| {
+                m.borrow().iter().filter(|entry| entry.value().owner == user).map(|entry| *entry.key()).take(limit).collect()
+            });
+            IMAGE_TOKENS_STABLE.with(|m| {
+                let mut m = m.borrow_mut();
+                for id in &ids {
+                    m.remove(id);
+                }
+            });
+            ids.len()
+        }
+        DataStore::Done => 0,
+    }
+}
+
+fn start_account_deletion(user: Principal) -> DeletionProgress {
+    if let Some(progress) = get_deletion_progress(user) {
+        if !progress.done {
+            return progress;
+        }
+    }
+    let started_at = time();
+    ACCOUNT_DELETION_STABLE.with(|map| map.borrow_mut().insert(user, (DataStore::Messages.id(), 0, started_at)));
+    schedule_account_deletion();
+    DeletionProgress { store: DataStore::Messages, removed: 0, started_at, done: false }
+}
+
+fn get_deletion_progress(user: Principal) -> Option<DeletionProgress> {
+    ACCOUNT_DELETION_STABLE
+        .with(|map| map.borrow().get(&user))
+        .map(|(store, removed, started_at)| {
+            let store = DataStore::from_id(store);
+            DeletionProgress { store, removed, started_at, done: store == DataStore::Done }
+        })
+}
+
+/// Przetwarza oczekujące usunięcia kont porcjami, dopóki starcza budżetu instrukcji.
+/// Zwraca true, jeśli została jeszcze praca na kolejne uruchomienie.
+fn process_account_deletions() -> bool {
+    let pending: Vec<(Principal, (u8, u64, u64))> = ACCOUNT_DELETION_STABLE.with(|map| {
+        map.borrow()
+            .iter()
+            .filter(|entry| DataStore::from_id(entry.value().0) != DataStore::Done)
+            .map(|entry| (*entry.key(), entry.value()))
+            .collect()
+    });
+
+    for (user, (store, mut removed, started_at)) in pending {
+        let mut store = DataStore::from_id(store);
+        while store != DataStore::Done {
+            if ic_cdk::api::instruction_counter() > DELETE_INSTRUCTION_BUDGET {
+                ACCOUNT_DELETION_STABLE.with(|map| map.borrow_mut().insert(user, (store.id(), removed, started_at)));
+                return true;
+            }
+            let count = delete_user_store_batch(user, store, DELETE_BATCH);
+            removed += count as u64;
+            if count < DELETE_BATCH {
+                store = store.next();
+            }
+        }
+        ACCOUNT_DELETION_STABLE.with(|map| map.borrow_mut().insert(user, (store.id(), removed, started_at)));
+    }
+    false
+}
+
+/// Usuwa wpisy o zakończonych usunięciach kont starsze niż `DELETION_TOMBSTONE_NANOS`.
+fn prune_deletion_tombstones(now: u64) {
+    let finished: Vec<Principal> = ACCOUNT_DELETION_STABLE.with(|map| {
+        map.borrow()
+            .iter()
+            .filter(|entry| {
+                let (store, _, started_at) = entry.value();
+                DataStore::from_id(store) == DataStore::Done && now.saturating_sub(started_at) >= DELETION_TOMBSTONE_NANOS
+            })
+            .map(|entry| *entry.key())
+            .collect()
+    });
+    ACCOUNT_DELETION_STABLE.with(|map| {
+        let mut map = map.borrow_mut();
+        for user in finished {
+            map.remove(&user);
+        }
+    });
+}
+
+fn schedule_account_deletion() {
+    let pending = ACCOUNT_DELETION_STABLE.with(|map| {
+        map.borrow().iter().any(|entry| DataStore::from_id(entry.value().0) != DataStore::Done)
+    });
+    if pending {
+        ic_cdk_timers::set_timer(Duration::ZERO, || {
+            if process_account_deletions() {
+                schedule_account_deletion();
+            }
+        });
+    }
+}
+
+type IntegrityKey = (Principal, [u8; 16], u32);
+
+fn chat_keys_after<V: Storable>(map: &StableBTreeMap<ChatKey, V, Memory>, after: Option<IntegrityKey>, limit: usize) -> Vec<IntegrityKey> {
+    let start = after.map_or(Bound::Unbounded, |(user, chat_id, _)| Bound::Excluded((user, chat_id)));
+    map.keys_range((start, Bound::Unbounded))
+        .take(limit)
+        .map(|(user, chat_id)| (user, chat_id, 0))
+        .collect()
+}
+
+fn msg_keys_after<V: Storable>(map: &StableBTreeMap<MessageKey, V, Memory>, after: Option<IntegrityKey>, limit: usize) -> Vec<IntegrityKey> {
+    let start = after.map_or(Bound::Unbounded, |(user, chat_id, msg_id)| Bound::Excluded(((user, chat_id), msg_id)));
+    map.keys_range((start, Bound::Unbounded))
+        .take(limit)
+        .map(|((user, chat_id), msg_id)| (user, chat_id, msg_id))
+        .collect()
+}
+
+fn integrity_keys(store: DataStore, after: Option<IntegrityKey>, limit: usize) -> Vec<IntegrityKey> {
+    match store {
+        DataStore::Chats => USER_CHATS_STABLE.with(|m| chat_keys_after(&m.borrow(), after, limit)),
+        DataStore::Archive => USER_ARCHIVE_STABLE.with(|m| chat_keys_after(&m.borrow(), after, limit)),
+        DataStore::Trash => USER_TRASH_STABLE.with(|m| chat_keys_after(&m.borrow(), after, limit)),
+        DataStore::Messages => CHAT_MESSAGES_STABLE.with(|m| msg_keys_after(&m.borrow(), after, limit)),
+        DataStore::Images => CHAT_IMAGES_STABLE.with(|m| msg_keys_after(&m.borrow(), after, limit)),
+        DataStore::Candidates => CHAT_CANDIDATES_STABLE.with(|m| msg_keys_after(&m.borrow(), after, limit)),
+        DataStore::ActiveLeaves => CHAT_ACTIVE_LEAF_STABLE.with(|m| chat_keys_after(&m.borrow(), after, limit)),
+        DataStore::Origins => CHAT_ORIGIN_STABLE.with(|m| chat_keys_after(&m.borrow(), after, limit)),
+        DataStore::Versions => CHAT_VERSIONS_STABLE.with(|m| chat_keys_after(&m.borrow(), after, limit)),
+        _ => Vec::new(),
+    }
+}
+
+fn next_integrity_store(store: DataStore) -> DataStore {
+    match store {
+        DataStore::Chats => DataStore::Archive,
+        DataStore::Archive => DataStore::Trash,
+        DataStore::Trash => DataStore::Messages,
+        DataStore::Messages => DataStore::Images,
+        DataStore::Images => DataStore::Candidates,
+        DataStore::Candidates => DataStore::ActiveLeaves,
+        DataStore::ActiveLeaves => DataStore::Origins,
+        DataStore::Origins => DataStore::Versions,
+        _ => DataStore::Done,
+    }
+}
+
+fn actual_msg_count(user: Principal, chat_id: [u8; 16]) -> u32 {
+    CHAT_MESSAGES_STABLE
+        .with(|map| map.borrow().keys_range(chat_msg_range(user, chat_id)).next_back())
+        .map_or(0, |(_, msg_id)| msg_id + 1)
+}
+
+/// Sprawdza jeden rekord; w trybie `repair` od razu go naprawia.
+fn check_integrity_entry(store: DataStore, (user, chat_id, msg_id): IntegrityKey, repair: bool) -> Option<(IntegrityProblem, bool)> {
+    let key = ((user, chat_id), msg_id);
+    match store {
+        DataStore::Chats | DataStore::Archive | DataStore::Trash => {
+            let stored = match store {
+                DataStore::Chats => USER_CHATS_STABLE.with(|m| m.borrow().get(&(user, chat_id))).map(|(_, count)| count),
+                DataStore::Archive => USER_ARCHIVE_STABLE.with(|m| m.borrow().get(&(user, chat_id))).map(|(_, count)| count),
+                _ => USER_TRASH_STABLE.with(|m| m.borrow().get(&(user, chat_id))).map(|(_, count, _)| count),
+            }?;
+            let actual = actual_msg_count(user, chat_id);
+            if stored == actual {
+                return None;
+            }
+            if repair {
+                match store {
+                    DataStore::Chats => USER_CHATS_STABLE.with(|m| {
+                        let mut m = m.borrow_mut();
+                        m.get(&(user, chat_id)).and_then(|(name, _)| m.insert((user, chat_id), (name, actual)))
+                    }),
+                    DataStore::Archive => USER_ARCHIVE_STABLE.with(|m| {
+                        let mut m = m.borrow_mut();
+                        m.get(&(user, chat_id)).and_then(|(name, _)| m.insert((user, chat_id), (name, actual)))
+                    }),
+                    _ => USER_TRASH_STABLE.with(|m| {
+                        let mut m = m.borrow_mut();
+                        m.get(&(user, chat_id))
+                            .and_then(|(name, _, deleted_at)| m.insert((user, chat_id), (name, actual, deleted_at)))
+                            .map(|(name, count, _)| (name, count))
+                    }),
+                };
+            }
+            Some((IntegrityProblem::MessageCountMismatch { stored, actual }, repair))
+        }
+        DataStore::Messages => {
+            if !chat_id_taken(user, chat_id) {
+                if repair {
+                    CHAT_MESSAGES_STABLE.with(|m| m.borrow_mut().remove(&key));
+                }
+                return Some((IntegrityProblem::OrphanMessage, repair));
+            }
+            let mut stored_message = get_stored_message(user, chat_id, msg_id)?;
+            if stored_message.image && !CHAT_IMAGES_STABLE.with(|m| m.borrow().contains_key(&key)) {
+                // bez obrazu wiadomość i tak byłaby pominięta; zostaje jako pusta wiadomość tekstowa
+                if repair {
+                    let kind = stored_kind(&stored_message);
+                    let role = if kind.role == MessageRole::Image { MessageRole::Assistant } else { kind.role };
+                    stored_message.image = false;
+                    stored_message.kind = Some(StoredKind { role, model: kind.model, content: StoredContent::Text });
+                    CHAT_MESSAGES_STABLE.with(|m| m.borrow_mut().insert(key, stored_message));
+                }
+                return Some((IntegrityProblem::MissingImage, repair));
+            }
+            let parent = message_parent(msg_id, &stored_message)?;
+            if get_stored_message(user, chat_id, parent).is_some() {
+                return None;
+            }
+            if repair {
+                stored_message.parent = Some(NO_PARENT);
+                CHAT_MESSAGES_STABLE.with(|m| m.borrow_mut().insert(key, stored_message));
+            }
+            Some((IntegrityProblem::DanglingParent { parent }, repair))
+        }
+        DataStore::Images => {
+            let linked = get_stored_message(user, chat_id, msg_id).is_some_and(|m| m.image) && chat_id_taken(user, chat_id);
+            if linked {
+                return None;
+            }
+            if repair {
+                CHAT_IMAGES_STABLE.with(|m| m.borrow_mut().remove(&key));
+            }
+            Some((IntegrityProblem::OrphanImage, repair))
+        }
+        DataStore::Candidates => {
+            if get_stored_message(user, chat_id, msg_id).is_some() && chat_id_taken(user, chat_id) {
+                return None;
+            }
+            if repair {
+                CHAT_CANDIDATES_STABLE.with(|m| m.borrow_mut().remove(&key));
+            }
+            Some((IntegrityProblem::OrphanCandidates, repair))
+        }
+        DataStore::ActiveLeaves | DataStore::Origins | DataStore::Versions => {
+            if !chat_id_taken(user, chat_id) {
+                if repair {
+                    match store {
+                        DataStore::ActiveLeaves => CHAT_ACTIVE_LEAF_STABLE.with(|m| m.borrow_mut().remove(&(user, chat_id))).map(|_| ()),
+                        DataStore::Origins => CHAT_ORIGIN_STABLE.with(|m| m.borrow_mut().remove(&(user, chat_id))).map(|_| ()),
+                        _ => CHAT_VERSIONS_STABLE.with(|m| m.borrow_mut().remove(&(user, chat_id))).map(|_| ()),
+                    };
+                }
+                return Some((IntegrityProblem::OrphanChatData, repair));
+            }
+            if store != DataStore::ActiveLeaves {
+                return None;
+            }
+            let leaf = CHAT_ACTIVE_LEAF_STABLE.with(|m| m.borrow().get(&(user, chat_id)))?;
+            if get_stored_message(user, chat_id, leaf).is_some() {
+                return None;
+            }
+            // bez wpisu aktywna gałąź wraca do ostatniej wiadomości
+            if repair {
+                CHAT_ACTIVE_LEAF_STABLE.with(|m| m.borrow_mut().remove(&(user, chat_id)));
+            }
+            Some((IntegrityProblem::DanglingActiveLeaf { leaf }, repair))
+        }
+        _ => None,
+    }
+}
+
+/// Przegląda magazyny po kolei, najwyżej `limit` rekordów na wywołanie.
+fn verify_integrity_batch(cursor: Option<IntegrityCursor>, limit: u32, repair: bool) -> IntegrityReport {
+    let limit = limit.clamp(1, INTEGRITY_MAX_BATCH) as usize;
+    let mut store = cursor.as_ref().map(|c| c.store).unwrap_or(DataStore::Chats);
+    let mut after = cursor.and_then(|c| match c.position {
+        IntegrityPosition::Start => None,
+        IntegrityPosition::After { user, chat_id, msg_id } => Some((user, chat_id, msg_id)),
+    });
+    let mut issues = Vec::new();
+    let mut scanned = 0;
+
+    while store != DataStore::Done && scanned < limit {
+        let remaining = limit - scanned;
+        let keys = integrity_keys(store, after, remaining);
+        for key in &keys {
+            if let Some((problem, repaired)) = check_integrity_entry(store, *key, repair) {
+                let (user, chat_id, msg_id) = *key;
+                // naprawa idzie jak zwykły zapis: nowa wersja czatu i wpis w dzienniku zmian
+                if repaired && problem.changes_chat() {
+                    bump_chat_version(user, chat_id);
+                    record_change(user, chat_id, ChangeKind::ChatRepaired);
+                }
+                let msg_id = matches!(store, DataStore::Messages | DataStore::Images | DataStore::Candidates).then_some(msg_id);
+                issues.push(IntegrityIssue { store, user, chat_id, msg_id, problem, repaired });
+            }
+        }
+        scanned += keys.len();
+        if keys.len() < remaining {
+            store = next_integrity_store(store);
+            after = None;
+        } else {
+            after = keys.last().copied();
+        }
+    }
+
+    let position = match after {
+        Some((user, chat_id, msg_id)) => IntegrityPosition::After { user, chat_id, msg_id },
+        None => IntegrityPosition::Start,
+    };
+    let next = (store != DataStore::Done).then_some(IntegrityCursor { store, position });
+    IntegrityReport { issues, scanned: scanned as u32, next }
+}
+
+fn upload_status(upload_id: [u8; 16], upload: StoredUpload) -> UploadStatus {
+    let ttl = get_setting(Setting::UploadTtlSecs).saturating_mul(1_000_000_000);
+    UploadStatus {
+        upload_id,
+        target: upload.target,
+        total_size: upload.total_size,
+        received: upload.received,
+        expires_at: upload.updated_at.saturating_add(ttl),
+    }
+}
+
+/// Sprawdza cel i rozmiar przed przyjęciem pierwszego fragmentu.
+fn check_upload_target(user: Principal, target: &UploadTarget, total_size: u64) -> Result<(), ApiError> {
+    let chat_id = target.chat_id();
+    check_storage(user, total_size)?;
+    if !USER_CHATS_STABLE.with(|map| map.borrow().contains_key(&(user, chat_id))) {
+        return Err(ApiError::NotFound("Chat not found".to_string()));
+    }
+    match target {
+        UploadTarget::NewImage { width, height, model, .. } => {
+            validate_image(*width, *height, "")?;
+            check_tier_image(user, *width, *height)?;
+            if let Some(model) = model {
+                validate_role_text(model)?;
+            }
+        }
+        UploadTarget::ReplaceImage { msg_id, width, height, .. } => {
+            if !CHAT_IMAGES_STABLE.with(|map| map.borrow().contains_key(&((user, chat_id), *msg_id))) {
+                return Err(ApiError::NotFound("Message not found".to_string()));
+            }
+            if let Some((width, height)) = replacement_size(*width, *height)? {
+                validate_image(width, height, "")?;
+                check_tier_image(user, width, height)?;
+            }
+        }
+        UploadTarget::NewMessage { role, model, .. } => {
+            check_message_kind(*role, &MessageContent::Text(String::new()))?;
+            if let Some(model) = model {
+                validate_role_text(model)?;
+            }
+        }
+    }
+    // upload służy właśnie treściom większym niż jedno wywołanie, więc ma własny limit
+    let max = get_setting(Setting::MaxUploadBytes);
+    if total_size == 0 || total_size > max {
+        return Err(ApiError::InvalidInput(format!("Upload size {} is outside 1..={}", total_size, max)));
+    }
+    Ok(())
+}
+
+fn replacement_size(width: Option<u32>, height: Option<u32>) -> Result<Option<(u32, u32)>, ApiError> {
+    match (width, height) {
+        (Some(width), Some(height)) => Ok(Some((width, height))),
+        (None, None) => Ok(None),
+        _ => Err(ApiError::InvalidInput("Image width and height must be given together".to_string())),
+    }
+}
+
+fn begin_upload_stable(user: Principal, upload_id: [u8; 16], target: UploadTarget, total_size: u64, sha256: [u8; 32]) -> Result<UploadStatus, ApiError> {
+    check_upload_target(user, &target, total_size)?;
+    let open = UPLOADS_STABLE.with(|map| map.borrow().keys_range(user_chat_range(user)).count());
+    if open >= MAX_OPEN_UPLOADS {
+        return Err(ApiError::Busy(format!("At most {} uploads can be open at once", MAX_OPEN_UPLOADS)));
+    }
+    let upload = StoredUpload { target, total_size, sha256, received: 0, updated_at: time() };
+    UPLOADS_STABLE.with(|map| map.borrow_mut().insert((user, upload_id), upload.clone()));
+    Ok(upload_status(upload_id, upload))
+}
+
+/// Zapisuje fragment; ponowne wysłanie tego samego numeru nadpisuje poprzednią wersję.
+fn upload_chunk_stable(user: Principal, upload_id: [u8; 16], index: u32, data: Vec<u8>) -> Result<UploadStatus, ApiError> {
+    let mut upload = UPLOADS_STABLE
+        .with(|map| map.borrow().get(&(user, upload_id)))
+        .ok_or_else(|| ApiError::NotFound("Upload not found".to_string()))?;
+    if data.is_empty() || data.len() > UPLOAD_CHUNK_MAX {
+        return Err(ApiError::InvalidInput(format!("Chunk size {} is outside 1..={}", data.len(), UPLOAD_CHUNK_MAX)));
+    }
+    let key = ((user, upload_id), index);
+    let previous = UPLOAD_CHUNKS_STABLE.with(|map| map.borrow().get(&key)).map_or(0, |chunk| chunk.len() as u64);
+    let received = upload.received - previous + data.len() as u64;
+    if received > upload.total_size {
+        return Err(ApiError::InvalidInput(format!("Upload would exceed declared size {}", upload.total_size)));
+    }
+    UPLOAD_CHUNKS_STABLE.with(|map| map.borrow_mut().insert(key, data));
+    upload.received = received;
+    upload.updated_at = time();
+    UPLOADS_STABLE.with(|map| map.borrow_mut().insert((user, upload_id), upload.clone()));
+    Ok(upload_status(upload_id, upload))
+}
+
+fn remove_upload(user: Principal, upload_id: [u8; 16]) -> bool {
+    UPLOAD_CHUNKS_STABLE.with(|map| {
+        remove_range_batch(&mut map.borrow_mut(), ((user, upload_id), 0)..=((user, upload_id), u32::MAX), usize::MAX)
+    });
+    UPLOADS_STABLE.with(|map| map.borrow_mut().remove(&(user, upload_id))).is_some()
+}
+
+/// Składa fragmenty 0..n, sprawdza rozmiar i sha256, po czym zapisuje treść do celu.
+/// Zwraca id wiadomości, do której trafiła treść.
+fn commit_upload_stable(user: Principal, upload_id: [u8; 16]) -> Result<u32, ApiError> {
+    let upload = UPLOADS_STABLE
+        .with(|map| map.borrow().get(&(user, upload_id)))
+        .ok_or_else(|| ApiError::NotFound("Upload not found".to_string()))?;
+    let mut content = Vec::with_capacity(upload.total_size as usize);
+    UPLOAD_CHUNKS_STABLE.with(|map| {
+        for (expected, entry) in map.borrow().range(((user, upload_id), 0)..=((user, upload_id), u32::MAX)).enumerate() {
+            let (_, index) = *entry.key();
+            if index as usize != expected {
+                return Err(ApiError::InvalidInput(format!("Chunk {} is missing", expected)));
+            }
+            content.extend_from_slice(&entry.value());
+        }
+        Ok(())
+    })?;
+    if content.len() as u64 != upload.total_size {
+        return Err(ApiError::InvalidInput(format!("Received {} of {} bytes", content.len(), upload.total_size)));
+    }
+    if sha256(&content) != upload.sha256 {
+        return Err(ApiError::InvalidInput("Checksum mismatch".to_string()));
+    }
+    let content = String::from_utf8(content)
+        .map_err(|e| ApiError::InvalidInput(format!("Upload is not valid UTF-8 at byte {}", e.utf8_error().valid_up_to())))?;
+
+    let msg_id = match upload.target {
+        UploadTarget::NewImage { chat_id, width, height, model } => {
+            // długość ograniczył już MaxUploadBytes w begin_upload
+            validate_image(width, height, "")?;
+            check_control_chars("Image data", &content, false)?;
+            let content = MessageContent::PixelImage { width, height, data: content };
+            let (_name, msg_count) = USER_CHATS_STABLE
+                .with(|map| map.borrow().get(&(user, chat_id)))
+                .ok_or_else(|| ApiError::NotFound("Chat not found".to_string()))?;
+            let parent = get_active_leaf(user, chat_id, msg_count);
+            insert_typed_message_stable(user, chat_id, parent, MessageRole::Image, content, model, time())
+                .ok_or_else(|| ApiError::NotFound("Chat not found".to_string()))?
+        }
+        UploadTarget::ReplaceImage { chat_id, msg_id, width, height } => {
+            let size = replacement_size(width, height)?;
+            if let Some((width, height)) = size {
+                validate_image(width, height, "")?;
+            }
+            check_control_chars("Image data", &content, false)?;
+            update_image_content(user, chat_id, msg_id, &content, size)?;
+            msg_id
+        }
+        UploadTarget::NewMessage { chat_id, role, model } => {
+            check_control_chars("Message", &content, true)?;
+            let content = MessageContent::Text(content);
+            let (_name, msg_count) = USER_CHATS_STABLE
+                .with(|map| map.borrow().get(&(user, chat_id)))
+                .ok_or_else(|| ApiError::NotFound("Chat not found".to_string()))?;
+            let parent = get_active_leaf(user, chat_id, msg_count);
+            insert_typed_message_stable(user, chat_id, parent, role, content, model, time())
+                .ok_or_else(|| ApiError::NotFound("Chat not found".to_string()))?
+        }
+    };
+    remove_upload(user, upload_id);
+    Ok(msg_id)
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_decode<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 || !s.is_ascii() {
+        return None;
+    }
+    let mut out = [0u8; N];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(out)
+}
+
+/// Kolory komórek w kolejności zapisu (`#RRGGBB`), tak jak czyta je HexGrid na froncie.
+fn parse_pixel_colors(data: &[u8]) -> Vec<[u8; 3]> {
+    let mut colors = Vec::new();
+    let mut i = 0;
+    while i + 7 <= data.len() {
+        if data[i] == b'#' {
+            let hex = std::str::from_utf8(&data[i + 1..i + 7]).ok();
+            if let Some(color) = hex.and_then(hex_decode::<3>) {
+                colors.push(color);
+                i += 7;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    colors
+}
+
+/// Kolor piksela (x, y) obrazu; front rysuje siatkę odbitą w obu osiach.
+fn pixel_at(colors: &[[u8; 3]], width: u32, height: u32, x: u32, y: u32) -> Option<[u8; 3]> {
+    let col = width - 1 - x;
+    let row = height - 1 - y;
+    colors.get((row * width + col) as usize).copied()
+}
+
+fn render_scale(width: u32, height: u32, requested: Option<u32>) -> u32 {
+    let pixels = width as u64 * height as u64;
+    let max_scale = ((MAX_RENDER_PIXELS / pixels.max(1)) as f64).sqrt() as u32;
+    requested.unwrap_or(DEFAULT_RENDER_SCALE).clamp(1, max_scale.max(1))
+}
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut c = 0xFFFF_FFFFu32;
+    for part in parts {
+        for byte in *part {
+            c = CRC32_TABLE[((c ^ *byte as u32) & 0xFF) as usize] ^ (c >> 8);
+        }
+    }
+    c ^ 0xFFFF_FFFF
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&crc32(&[kind, data]).to_be_bytes());
+}
+
+/// PNG RGBA bez kompresji: strumień zlib z blokami deflate typu "stored".
+fn encode_png(width: u32, height: u32, rgba_rows: &[u8]) -> Vec<u8> {
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = rgba_rows.chunks(65_535).peekable();
+    while let Some(block) = blocks.next() {
+        zlib.push(if blocks.peek().is_none() { 1 } else { 0 });
+        let len = block.len() as u16;
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(rgba_rows).to_be_bytes());
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    // 8 bitów na kanał, RGBA, bez przeplotu
+    ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+    let mut png = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+    png_chunk(&mut png, b"IHDR", &ihdr);
+    png_chunk(&mut png, b"IDAT", &zlib);
+    png_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn render_png(image: &StoredImage, scale: u32) -> Vec<u8> {
+    let colors = parse_pixel_colors(&image.data);
+    let (out_w, out_h) = (image.width * scale, image.height * scale);
+    let mut rows = Vec::with_capacity(((out_w * 4 + 1) * out_h) as usize);
+    for out_y in 0..out_h {
+        // filtr "None" na początku każdego wiersza
+        rows.push(0);
+        for out_x in 0..out_w {
+            match pixel_at(&colors, image.width, image.height, out_x / scale, out_y / scale) {
+                Some([r, g, b]) => rows.extend_from_slice(&[r, g, b, 255]),
+                None => rows.extend_from_slice(&[0, 0, 0, 0]),
+            }
+        }
+    }
+    encode_png(out_w, out_h, &rows)
+}
+
+/// SVG z prostokątami; sąsiednie piksele w wierszu o tym samym kolorze są łączone.
+fn render_svg(image: &StoredImage, scale: u32) -> Vec<u8> {
+    let colors = parse_pixel_colors(&image.data);
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {w} {h}\" width=\"{sw}\" height=\"{sh}\" shape-rendering=\"crispEdges\">",
+        w = image.width,
+        h = image.height,
+        sw = image.width * scale,
+        sh = image.height * scale,
+    );
+    for y in 0..image.height {
+        let mut x = 0;
+        while x < image.width {
+            let color = pixel_at(&colors, image.width, image.height, x, y);
+            let mut run = 1;
+            while x + run < image.width && pixel_at(&colors, image.width, image.height, x + run, y) == color {
+                run += 1;
+            }
+            if let Some(color) = color {
+                svg.push_str(&format!("<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"1\" fill=\"#{}\"/>", x, y, run, hex_encode(&color)));
+            }
+            x += run;
+        }
+    }
+    svg.push_str("</svg>");
+    svg.into_bytes()
+}
+
+fn share_link_info(id: [u8; 32], link: StoredShareLink, token: Option<String>) -> ShareLink {
+    ShareLink { id, token, chat_id: link.chat_id, msg_id: link.msg_id, created_at: link.created_at, expires_at: link.expires_at }
+}
+
+fn create_share_link_stable(user: Principal, token: String, chat_id: [u8; 16], msg_id: Option<u32>, ttl_secs: Option<u64>) -> Result<ShareLink, ApiError> {
+    if !chat_exists(user, chat_id) {
+        return Err(ApiError::NotFound("Chat not found".to_string()));
+    }
+    if let Some(msg_id) = msg_id {
+        if !CHAT_IMAGES_STABLE.with(|map| map.borrow().contains_key(&((user, chat_id), msg_id))) {
+            return Err(ApiError::NotFound("Image not found".to_string()));
+        }
+    }
+    let now = time();
+    let ids: Vec<[u8; 32]> = SHARE_LINKS_BY_USER_STABLE.with(|map| {
+        map.borrow().keys_range((user, [0u8; 32])..=(user, [u8::MAX; 32])).map(|(_, id)| id).collect()
+    });
+    // wygasłe linki nie zajmują limitu; sprzątamy je od razu zamiast czekać na timer
+    let mut open = 0;
+    for id in ids {
+        let expired = SHARE_LINKS_STABLE
+            .with(|map| map.borrow().get(&id))
+            .is_none_or(|link| link.expires_at.is_some_and(|expires_at| now >= expires_at));
+        if expired {
+            revoke_share_link_stable(user, id);
+        } else {
+            open += 1;
+        }
+    }
+    if open >= MAX_SHARE_LINKS {
+        return Err(ApiError::Busy(format!("At most {} share links can exist at once", MAX_SHARE_LINKS)));
+    }
+    let id = sha256(token.as_bytes());
+    let link = StoredShareLink {
+        owner: user,
+        chat_id,
+        msg_id,
+        created_at: now,
+        expires_at: ttl_secs.map(|ttl| now.saturating_add(ttl.saturating_mul(1_000_000_000))),
+    };
+    SHARE_LINKS_STABLE.with(|map| map.borrow_mut().insert(id, link.clone()));
+    SHARE_LINKS_BY_USER_STABLE.with(|map| map.borrow_mut().insert((user, id), ()));
+    Ok(share_link_info(id, link, Some(token)))
+}
+
+fn list_share_links_for_user(user: Principal) -> Vec<ShareLink> {
+    let ids: Vec<[u8; 32]> = SHARE_LINKS_BY_USER_STABLE.with(|map| {
+        map.borrow().keys_range((user, [0u8; 32])..=(user, [u8::MAX; 32])).map(|(_, id)| id).collect()
+    });
+    ids.into_iter()
+        .filter_map(|id| SHARE_LINKS_STABLE.with(|map| map.borrow().get(&id)).map(|link| share_link_info(id, link, None)))
+        .collect()
+}
+
+fn revoke_share_link_stable(user: Principal, id: [u8; 32]) -> bool {
+    if SHARE_LINKS_BY_USER_STABLE.with(|map| map.borrow_mut().remove(&(user, id))).is_none() {
+        return false;
+    }
+    SHARE_LINKS_STABLE.with(|map| map.borrow_mut().remove(&id));
+    true
+}
+
+/// Usuwa wygasłe linki udostępniania, najwyżej `limit` na wywołanie.
+fn expire_share_links(now: u64, limit: usize) -> u32 {
+    let expired: Vec<(Principal, [u8; 32])> = SHARE_LINKS_STABLE.with(|map| {
+        map.borrow()
+            .iter()
+            .filter(|entry| entry.value().expires_at.is_some_and(|expires_at| now >= expires_at))
+            .map(|entry| (entry.value().owner, *entry.key()))
+            .take(limit)
+            .collect()
+    });
+    expired.into_iter().filter(|(owner, id)| revoke_share_link_stable(*owner, *id)).count() as u32
+}
+
+fn create_image_token_stable(user: Principal, token: String, chat_id: [u8; 16]) -> Result<ImageToken, ApiError> {
+    if !chat_exists(user, chat_id) {
+        return Err(ApiError::NotFound("Chat not found".to_string()));
+    }
+    let expires_at = time().saturating_add(IMAGE_TOKEN_TTL_NANOS);
+    IMAGE_TOKENS_STABLE.with(|map| {
+        map.borrow_mut().insert(sha256(token.as_bytes()), StoredImageToken { owner: user, chat_id, expires_at })
+    });
+    Ok(ImageToken { token, chat_id, expires_at })
+}
+
+/// Usuwa wygasłe tokeny właściciela; żyją kwadrans, więc tabela jest mała.
+fn expire_image_tokens(now: u64) -> u32 {
+    let expired: Vec<[u8; 32]> = IMAGE_TOKENS_STABLE.with(|map| {
+        map.borrow().iter().filter(|entry| now >= entry.value().expires_at).map(|entry| *entry.key()).collect()
+    });
+    IMAGE_TOKENS_STABLE.with(|map| {
+        let mut map = map.borrow_mut();
+        expired.iter().filter(|id| map.remove(id).is_some()).count() as u32
+    })
+}
+
+/// Właściciel obrazu dla żądania HTTP: klucz API (tylko z nagłówka `Authorization`), ważny token
+/// udostępniania albo token właściciela z `create_image_token`. Bramka HTTP woła zapytania
+/// anonimowo, więc `caller()` nic tu nie mówi.
+fn http_image_owner(query_token: Option<&str>, bearer: Option<&str>, chat_id: [u8; 16], msg_id: u32) -> Option<(Principal, bool)> {
+    if let Some(key) = bearer.filter(|token| token.starts_with("sk-")) {
+        return api_key_owner(key, ApiKeyScope::ReadOnly).ok().map(|owner| (owner, false));
+    }
+    // długotrwałe klucze nie mogą trafiać do URL-i (logi, referer)
+    let token = query_token.or(bearer).filter(|token| !token.starts_with("sk-"))?;
+    let id = sha256(token.as_bytes());
+    if let Some(owner_token) = IMAGE_TOKENS_STABLE.with(|map| map.borrow().get(&id)) {
+        let valid = time() < owner_token.expires_at && owner_token.chat_id == chat_id && !is_banned(owner_token.owner);
+        return valid.then_some((owner_token.owner, false));
+    }
+    let link = SHARE_LINKS_STABLE.with(|map| map.borrow().get(&id))?;
+    let expired = link.expires_at.is_some_and(|expires_at| time() >= expires_at);
+    if expired || is_banned(link.owner) || link.chat_id != chat_id || link.msg_id.is_some_and(|id| id != msg_id) {
+        return None;
+    }
+    Some((link.owner, true))
+}
+
+fn http_response(status_code: u16, headers: Vec<(String, String)>, body: Vec<u8>) -> HttpResponse {
+    HttpResponse { status_code, headers, body, upgrade: None }
+}
 
-    static USER_ARCHIVE_STABLE: RefCell<StableBTreeMap<(Principal, [u8; 16]), ([u8; 64], u32), Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
-        )
-    );
+fn http_error(status_code: u16, message: &str) -> HttpResponse {
+    http_response(
+        status_code,
+        vec![("Content-Type".to_string(), "text/plain; charset=utf-8".to_string())],
+        message.as_bytes().to_vec(),
+    )
+}
 
-    static CHAT_MESSAGES_STABLE: RefCell<StableBTreeMap<((Principal, [u8; 16]), u32), StoredMessage, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
-        )
-    );
+fn http_header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+}
 
-    static CHAT_IMAGES_STABLE: RefCell<StableBTreeMap<((Principal, [u8; 16]), u32), StoredImage, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
-        )
-    );
+/// Rozbija URL na ścieżkę i parametry zapytania (bez dekodowania procentowego; tokeny są hex).
+fn split_url(url: &str) -> (&str, Vec<(&str, &str)>) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let params = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+        .collect();
+    (path, params)
 }
 
-pub fn update_image_content(
-    user: Principal,
-    chat_id: [u8; 16],
-    msg_id: u32,
-    new_content: &str,
-) -> Result<(), String> {
-    CHAT_IMAGES_STABLE.with(|map| {
+/// GET /image/{chat_hex}/{msg}.png|.svg[?token=...&scale=N]; klucze API tylko w nagłówku `Authorization: Bearer`.
+///
+/// Odpowiedzi nie są certyfikowane (treść prywatna i zmienna, bez drzewa certyfikacji), więc
+/// obrazy trzeba osadzać przez domenę surową: `https://<canister-id>.raw.icp0.io/image/...`.
+/// Zwykła bramka `icp0.io` je odrzuci.
+fn http_image(req: &HttpRequest, path: &str, params: &[(&str, &str)]) -> HttpResponse {
+    let Some(rest) = path.strip_prefix("/image/") else {
+        return http_error(404, "Not found");
+    };
+    let Some((chat_hex, file)) = rest.split_once('/') else {
+        return http_error(404, "Not found");
+    };
+    let Some((msg, extension)) = file.rsplit_once('.') else {
+        return http_error(404, "Not found");
+    };
+    let (Some(chat_id), Ok(msg_id)) = (hex_decode::<16>(chat_hex), msg.parse::<u32>()) else {
+        return http_error(404, "Not found");
+    };
+    let param = |name: &str| params.iter().find(|(key, _)| *key == name).map(|(_, value)| *value);
+    let Some((owner, shared)) = http_image_owner(param("token"), bearer_token(req), chat_id, msg_id) else {
+        return http_error(401, "Unauthorized");
+    };
+    let Some(image) = CHAT_IMAGES_STABLE.with(|map| map.borrow().get(&((owner, chat_id), msg_id))) else {
+        return http_error(404, "Not found");
+    };
+    if !chat_exists(owner, chat_id) {
+        return http_error(404, "Not found");
+    }
+
+    let scale = render_scale(image.width, image.height, param("scale").and_then(|v| v.parse().ok()));
+    let etag = format!("\"{}-{}-{}\"", hex_encode(&sha256(&image.data)[..16]), extension, scale);
+    let cache_control = if shared { "public, max-age=300" } else { "private, max-age=300" };
+    let mut headers = vec![
+        ("Cache-Control".to_string(), cache_control.to_string()),
+        ("ETag".to_string(), etag.clone()),
+    ];
+    if http_header(req, "If-None-Match") == Some(etag.as_str()) {
+        return http_response(304, headers, Vec::new());
+    }
+    let (content_type, body) = match extension {
+        "png" => ("image/png", render_png(&image, scale)),
+        "svg" => ("image/svg+xml", render_svg(&image, scale)),
+        _ => return http_error(404, "Not found"),
+    };
+    headers.push(("Content-Type".to_string(), content_type.to_string()));
+    http_response(200, headers, body)
+}
+
+const NANOS_PER_DAY: u64 = 86_400 * 1_000_000_000;
+
+fn api_key_info(id: [u8; 32], key: StoredApiKey, token: Option<String>) -> ApiKey {
+    ApiKey {
+        id,
+        token,
+        name: key.name.clone().unwrap_or_default(),
+        scope: key.scope(),
+        daily_limit: key.daily_limit,
+        used_today: key.used_on(time() / NANOS_PER_DAY),
+        created_at: key.created_at,
+        last_used_at: key.last_used_at,
+    }
+}
+
+fn create_api_key_stable(user: Principal, token: String, name: String, scope: ApiKeyScope, daily_limit: Option<u32>) -> Result<ApiKey, ApiError> {
+    validate_name("Key name", &name, Setting::MaxChatNameBytes, 64)?;
+    let count = API_KEYS_BY_USER_STABLE.with(|map| map.borrow().keys_range((user, [0u8; 32])..=(user, [u8::MAX; 32])).count());
+    if count >= MAX_API_KEYS {
+        return Err(ApiError::Busy(format!("At most {} API keys can exist at once", MAX_API_KEYS)));
+    }
+    let id = sha256(token.as_bytes());
+    let key = StoredApiKey {
+        owner: user,
+        created_at: time(),
+        name: Some(name),
+        scope: Some(scope),
+        daily_limit,
+        usage_day: None,
+        usage_count: None,
+        last_used_at: None,
+    };
+    API_KEYS_STABLE.with(|map| map.borrow_mut().insert(id, key.clone()));
+    API_KEYS_BY_USER_STABLE.with(|map| map.borrow_mut().insert((user, id), ()));
+    Ok(api_key_info(id, key, Some(token)))
+}
+
+fn list_api_keys_for_user(user: Principal) -> Vec<ApiKey> {
+    let ids: Vec<[u8; 32]> = API_KEYS_BY_USER_STABLE.with(|map| {
+        map.borrow().keys_range((user, [0u8; 32])..=(user, [u8::MAX; 32])).map(|(_, id)| id).collect()
+    });
+    ids.into_iter()
+        .filter_map(|id| API_KEYS_STABLE.with(|map| map.borrow().get(&id)).map(|key| api_key_info(id, key, None)))
+        .collect()
+}
+
+fn revoke_api_key_stable(user: Principal, id: [u8; 32]) -> bool {
+    if API_KEYS_BY_USER_STABLE.with(|map| map.borrow_mut().remove(&(user, id))).is_none() {
+        return false;
+    }
+    API_KEYS_STABLE.with(|map| map.borrow_mut().remove(&id));
+    true
+}
+
+/// Właściciel klucza, jeśli klucz istnieje i jego zakres pozwala na `needed`.
+fn api_key_owner(token: &str, needed: ApiKeyScope) -> Result<Principal, ApiError> {
+    let key = API_KEYS_STABLE.with(|map| map.borrow().get(&sha256(token.trim().as_bytes())))
+        .ok_or_else(|| ApiError::Unauthorized("Invalid API key".to_string()))?;
+    if is_banned(key.owner) {
+        return Err(ApiError::Unauthorized("API key owner is banned".to_string()));
+    }
+    let scope = key.scope();
+    if needed != ApiKeyScope::ReadOnly && scope != needed {
+        return Err(ApiError::Unauthorized(format!("API key scope {:?} does not allow {:?}", scope, needed)));
+    }
+    Ok(key.owner)
+}
+
+/// Zapisuje czas użycia klucza i zwraca jego id; prompty rozlicza `reserve_prompt_stable`.
+fn record_api_key_use(token: &str) -> Result<[u8; 32], ApiError> {
+    let id = sha256(token.trim().as_bytes());
+    let mut key = API_KEYS_STABLE.with(|map| map.borrow().get(&id))
+        .ok_or_else(|| ApiError::Unauthorized("Invalid API key".to_string()))?;
+    key.last_used_at = Some(time());
+    API_KEYS_STABLE.with(|map| map.borrow_mut().insert(id, key));
+    Ok(id)
+}
+
+/// Zmienia dzienny licznik klucza; zwrot działa tylko w dniu, w którym prompt został policzony.
+fn add_api_key_usage(id: [u8; 32], day: u64, delta: i32) {
+    API_KEYS_STABLE.with(|map| {
         let mut map = map.borrow_mut();
-        let key = ((user, chat_id), msg_id);
+        if let Some(mut key) = map.get(&id) {
+            if delta < 0 && key.usage_day != Some(day) {
+                return;
+            }
+            key.usage_count = Some(key.used_on(day).saturating_add_signed(delta));
+            key.usage_day = Some(day);
+            map.insert(id, key);
+        }
+    });
+}
 
-        if let Some(stored_image) = map.get(&key) {
-            let mut stored_copy = stored_image.clone();
-            // konwersja nowego contentu do [u8; 100000]
-            /*let bytes = new_content.as_bytes();
-            if bytes.len() > 100000 {
-                return Err("New content too large for buffer (max 100000 bytes)".to_string());
-            }*/
-            stored_copy.data = string_to_bytes(new_content);
+/// Oddaje prompt do puli; zdejmuje blokadę, jeśli to ten prompt ją założył.
+fn refund_quota(user: Principal, pool: QuotaPool) {
+    if let Some((count, blocked)) = quota_record(user, pool) {
+        let count = count.saturating_sub(1);
+        let blocked = bytes_to_option_f64(&blocked).filter(|_| count >= quota_limit(user, pool));
+        set_quota_record(user, pool, (count, option_f64_to_bytes(blocked)));
+    }
+}
 
-            /*let mut buf = [0u8; 100000];
-            buf[..bytes.len()].copy_from_slice(bytes);*/
+/// Zużywa prompt od razu (żeby równoległe wywołania nie przekroczyły limitu) i zapisuje rezerwację,
+/// która przy niepowodzeniu albo po wygaśnięciu oddaje go z powrotem.
+async fn reserve_prompt_stable(user: Principal, pool: QuotaPool, api_key: Option<[u8; 32]>) -> Result<PromptReservation, ApiError> {
+    let id = new_random_id(|id| PROMPT_RESERVATIONS_STABLE.with(|map| map.borrow().contains_key(&(user, id)))).await?;
+    let now = time();
+    expire_user_reservations(user, now);
+    let day = now / NANOS_PER_DAY;
+    if let Some(key_id) = api_key {
+        let key = API_KEYS_STABLE.with(|map| map.borrow().get(&key_id))
+            .ok_or_else(|| ApiError::Unauthorized("Invalid API key".to_string()))?;
+        if key.daily_limit.is_some_and(|limit| key.used_on(day) >= limit) {
+            add_counter(Counter::QuotaBlocks, "api_key_daily", 1);
+            return Err(ApiError::Busy("API key daily quota exceeded".to_string()));
+        }
+    }
+    if !inc_quota_stable(user, pool) {
+        return Err(ApiError::Busy(format!("Quota for {} exceeded", pool.name())));
+    }
+    if let Some(key_id) = api_key {
+        add_api_key_usage(key_id, day, 1);
+    }
+    let expires_at = now.saturating_add(get_setting(Setting::PromptReservationTtlSecs).saturating_mul(1_000_000_000));
+    PROMPT_RESERVATIONS_STABLE.with(|map| {
+        map.borrow_mut().insert((user, id), StoredReservation { created_at: now, expires_at, api_key, pool: Some(pool), started_at: None })
+    });
+    Ok(PromptReservation { id, expires_at })
+}
 
-            // wstawiamy z powrotem nową wartość z tym samym meta
-            map.insert(key, stored_copy);
-            Ok(())
-        } else {
-            Err("Message not found".to_string())
+/// Zajmuje rezerwację klienta na jedno wywołanie LLM; brakującą, już zajętą albo z innej puli
+/// zastępuje nową rezerwacją z `pool`. Zajęta rezerwacja, której nikt nie rozliczył (trap po
+/// await), wygasa po `LLM_CALL_TIMEOUT_NANOS` i oddaje prompt.
+async fn claim_reservation(user: Principal, id: Option<[u8; 16]>, pool: QuotaPool) -> Result<[u8; 16], ApiError> {
+    let now = time();
+    let usable = id.filter(|id| {
+        PROMPT_RESERVATIONS_STABLE.with(|map| map.borrow().get(&(user, *id))).is_some_and(|reservation| {
+            reservation.pool.unwrap_or(QuotaPool::Chat) == pool && reservation.started_at.is_none() && reservation.expires_at > now
+        })
+    });
+    let id = match usable {
+        Some(id) => id,
+        None => reserve_prompt_stable(user, pool, None).await?.id,
+    };
+    PROMPT_RESERVATIONS_STABLE.with(|map| {
+        let mut map = map.borrow_mut();
+        if let Some(mut reservation) = map.get(&(user, id)) {
+            let now = time();
+            reservation.started_at = Some(now);
+            reservation.expires_at = now.saturating_add(LLM_CALL_TIMEOUT_NANOS);
+            map.insert((user, id), reservation);
         }
-    })
+    });
+    Ok(id)
 }
 
-fn set_chat_archived_stable(user: Principal, chat_id: [u8; 16], archived: bool) -> bool {
-    if archived {
-        // przenieś do archiwum
-        USER_CHATS_STABLE.with(|map| {
-            let mut map = map.borrow_mut();
-            if let Some((name, msg_count)) = map.remove(&(user, chat_id)) {
-                USER_ARCHIVE_STABLE.with(|archive| {
-                    archive
-                        .borrow_mut()
-                        .insert((user, chat_id), (name, msg_count));
-                });
-                true
-            } else {
-                false
+/// Rozlicza rezerwację: sukces ją zatwierdza, porażka oddaje prompt. Fałsz, gdy rezerwacji już nie ma.
+fn settle_reservation(user: Principal, id: [u8; 16], success: bool) -> bool {
+    let Some(reservation) = PROMPT_RESERVATIONS_STABLE.with(|map| map.borrow_mut().remove(&(user, id))) else {
+        return false;
+    };
+    if !success {
+        refund_quota(user, reservation.pool.unwrap_or(QuotaPool::Chat));
+        if let Some(key_id) = reservation.api_key {
+            add_api_key_usage(key_id, reservation.created_at / NANOS_PER_DAY, -1);
+        }
+    }
+    true
+}
+
+fn expire_user_reservations(user: Principal, now: u64) {
+    let expired: Vec<[u8; 16]> = PROMPT_RESERVATIONS_STABLE.with(|map| {
+        map.borrow()
+            .range(user_chat_range(user))
+            .filter(|entry| entry.value().expires_at <= now)
+            .map(|entry| entry.key().1)
+            .collect()
+    });
+    for id in expired {
+        settle_reservation(user, id, false);
+    }
+}
+
+fn expire_reservations(now: u64, limit: usize) -> u32 {
+    let expired: Vec<(Principal, [u8; 16])> = PROMPT_RESERVATIONS_STABLE.with(|map| {
+        map.borrow()
+            .iter()
+            .filter(|entry| entry.value().expires_at <= now)
+            .map(|entry| *entry.key())
+            .take(limit)
+            .collect()
+    });
+    expired
+        .into_iter()
+        .filter(|(user, id)| settle_reservation(*user, *id, false))
+        .count() as u32
+}
+
+/// Token z nagłówka `Authorization: Bearer ...`.
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    http_header(req, "Authorization")?.strip_prefix("Bearer ")
+}
+
+fn memory_pages(id: u8) -> u64 {
+    MEMORY_MANAGER.with(|m| ic_stable_structures::Memory::size(&m.borrow().get(MemoryId::new(id))))
+}
+
+fn heap_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        (arch::wasm32::memory_size(0) * 65_536) as u64
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+fn metric_family(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, u64)]) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+    for (labels, value) in samples {
+        out.push_str(&format!("{}{} {}\n", name, labels, value));
+    }
+}
+
+fn labeled(label: &str, samples: Vec<(String, u64)>) -> Vec<(String, u64)> {
+    samples.into_iter().map(|(value, n)| (format!("{{{}=\"{}\"}}", label, value), n)).collect()
+}
+
+/// GET /metrics w formacie tekstowym Prometheusa.
+fn render_metrics() -> String {
+    let single = |value: u64| vec![(String::new(), value)];
+    let mut out = String::new();
+    metric_family(&mut out, "chatgpt_users", "gauge", "Users with a prompt quota record.",
+        &single(USER_PROMPTS_STABLE.with(|m| m.borrow().len())));
+    metric_family(&mut out, "chatgpt_chats", "gauge", "Chats by state.", &labeled("state", vec![
+        ("active".to_string(), USER_CHATS_STABLE.with(|m| m.borrow().len())),
+        ("archived".to_string(), USER_ARCHIVE_STABLE.with(|m| m.borrow().len())),
+        ("trash".to_string(), USER_TRASH_STABLE.with(|m| m.borrow().len())),
+    ]));
+    metric_family(&mut out, "chatgpt_messages", "gauge", "Stored messages.",
+        &single(CHAT_MESSAGES_STABLE.with(|m| m.borrow().len())));
+    metric_family(&mut out, "chatgpt_images", "gauge", "Stored images.",
+        &single(CHAT_IMAGES_STABLE.with(|m| m.borrow().len())));
+    metric_family(&mut out, "chatgpt_llm_calls_total", "counter", "LLM calls by model.",
+        &labeled("model", counters(Counter::LlmCalls)));
+    metric_family(&mut out, "chatgpt_llm_failures_total", "counter", "Failed or empty LLM calls by model.",
+        &labeled("model", counters(Counter::LlmFailures)));
+    metric_family(&mut out, "chatgpt_quota_blocks_total", "counter", "Requests rejected by a quota.",
+        &labeled("quota", counters(Counter::QuotaBlocks)));
+    let pages: Vec<(String, u64)> = MEMORY_STORES.iter()
+        .map(|(id, name)| (format!("{{memory_id=\"{}\",store=\"{}\"}}", id, name), memory_pages(*id)))
+        .collect();
+    metric_family(&mut out, "chatgpt_stable_memory_pages", "gauge", "Stable memory pages (64 KiB) per MemoryId.", &pages);
+    metric_family(&mut out, "chatgpt_heap_bytes", "gauge", "Wasm heap size in bytes.", &single(heap_bytes()));
+    metric_family(&mut out, "chatgpt_cycle_balance", "gauge", "Canister cycle balance.",
+        &single(u64::try_from(ic_cdk::api::canister_balance128()).unwrap_or(u64::MAX)));
+    out
+}
+
+fn llm_health() -> LlmHealth {
+    let mut health = LLM_HEALTH.with(|h| h.borrow().clone());
+    let now = time();
+    let success = health.last_success.unwrap_or(0);
+    // próba bez odpowiedzi dłuższa niż timeout oznacza wywołanie zakończone trapem
+    let timed_out = health.last_attempt.filter(|&attempt| attempt > success && now.saturating_sub(attempt) > LLM_CALL_TIMEOUT_NANOS);
+    if timed_out.is_some() && health.last_failure < timed_out {
+        health.last_failure = timed_out;
+    }
+    health.reachable = match (health.last_success, health.last_failure) {
+        (None, None) => None,
+        (success, failure) => Some(success > failure),
+    };
+    health
+}
+
+fn canister_status() -> CanisterStatus {
+    let started_at = STARTED_AT.with(|t| *t.borrow());
+    let now = time();
+    CanisterStatus {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_commit: option_env!("GIT_COMMIT").map(str::to_string),
+        schema_version: SCHEMA_VERSION_STABLE.with(|cell| *cell.borrow().get()),
+        started_at,
+        uptime_secs: now.saturating_sub(started_at) / 1_000_000_000,
+        stores: MEMORY_STORES.iter()
+            .map(|(id, name)| {
+                let pages = memory_pages(*id);
+                StoreUsage { memory_id: *id, name: name.to_string(), pages, bytes: pages * 65_536 }
+            })
+            .collect(),
+        stable_memory_pages: ic_cdk::api::stable::stable64_size(),
+        heap_bytes: heap_bytes(),
+        cycle_balance: ic_cdk::api::canister_balance128(),
+        llm: llm_health(),
+    }
+}
+
+fn user_summary(user: Principal) -> UserSummary {
+    let chat_range = (user, [0u8; 16])..=(user, [u8::MAX; 16]);
+    let (prompts_used, blocked) = USER_PROMPTS_STABLE.with(|map| map.borrow().get(&user)).unwrap_or((0, option_f64_to_bytes(None)));
+    UserSummary {
+        user,
+        name: get_name_stable(user).map(|b| fixed_bytes_to_string(&b)),
+        ban: BANNED_STABLE.with(|map| map.borrow().get(&user)),
+        tier: user_tier(user),
+        chats: USER_CHATS_STABLE.with(|map| map.borrow().keys_range(chat_range.clone()).count()) as u32,
+        archived_chats: USER_ARCHIVE_STABLE.with(|map| map.borrow().keys_range(chat_range.clone()).count()) as u32,
+        trashed_chats: USER_TRASH_STABLE.with(|map| map.borrow().keys_range(chat_range).count()) as u32,
+        storage_bytes: STORAGE_USAGE.with(|cache| cache.borrow().get(&user).map(|(bytes, _)| *bytes)),
+        prompts_used,
+        prompt_limit: prompt_limit(user),
+        blocked_since: bytes_to_option_f64(&blocked),
+    }
+}
+
+/// Kolejni właściciele czatów po `after`: jeden skok zakresu na użytkownika, bez przeglądania czatów.
+fn chat_owners_after<V: Storable>(map: &StableBTreeMap<ChatKey, V, Memory>, after: Option<Principal>, limit: usize) -> Vec<Principal> {
+    let mut owners = Vec::new();
+    let mut cursor = after;
+    while owners.len() < limit {
+        let start = cursor.map_or(Bound::Unbounded, |owner| Bound::Excluded((owner, [u8::MAX; 16])));
+        let Some((owner, _)) = map.keys_range((start, Bound::Unbounded)).next() else {
+            break;
+        };
+        owners.push(owner);
+        cursor = Some(owner);
+    }
+    owners
+}
+
+/// Użytkownicy to principale z rekordem limitu promptów albo z jakimkolwiek czatem
+/// (aktywnym, zarchiwizowanym lub w koszu).
+fn list_users_page(after: Option<Principal>, limit: u32) -> UserPage {
+    let limit = limit.clamp(1, ADMIN_MAX_PAGE) as usize;
+    let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+    let mut users: BTreeSet<Principal> = USER_PROMPTS_STABLE.with(|map| {
+        map.borrow().keys_range((start, Bound::Unbounded)).take(limit + 1).collect()
+    });
+    users.extend(USER_CHATS_STABLE.with(|map| chat_owners_after(&map.borrow(), after, limit + 1)));
+    users.extend(USER_ARCHIVE_STABLE.with(|map| chat_owners_after(&map.borrow(), after, limit + 1)));
+    users.extend(USER_TRASH_STABLE.with(|map| chat_owners_after(&map.borrow(), after, limit + 1)));
+    let mut users: Vec<Principal> = users.into_iter().take(limit + 1).collect();
+    let next = (users.len() > limit).then(|| users[limit - 1]);
+    users.truncate(limit);
+    UserPage { users: users.into_iter().map(user_summary).collect(), next }
+}
+
+fn reset_prompt_quota_stable(user: Principal) {
+    USER_PROMPTS_STABLE.with(|map| {
+        let mut map = map.borrow_mut();
+        if map.contains_key(&user) {
+            map.insert(user, (0, option_f64_to_bytes(None)));
+        }
+    });
+}
+
+/// Ustawia limit użytkownika (None przywraca domyślny); zdejmuje blokadę, jeśli nowy limit nie jest osiągnięty.
+fn set_prompt_limit_stable(user: Principal, limit: Option<u32>) {
+    PROMPT_LIMITS_STABLE.with(|map| match limit {
+        Some(limit) => map.borrow_mut().insert(user, limit),
+        None => map.borrow_mut().remove(&user),
+    });
+    USER_PROMPTS_STABLE.with(|map| {
+        let mut map = map.borrow_mut();
+        if let Some((count, blocked)) = map.get(&user) {
+            if bytes_to_option_f64(&blocked).is_some() && count < prompt_limit(user) {
+                map.insert(user, (count, option_f64_to_bytes(None)));
             }
+        }
+    });
+}
+
+fn ban_user_stable(user: Principal, reason: String) -> Result<(), ApiError> {
+    if ic_cdk::api::is_controller(&user) {
+        return Err(ApiError::InvalidInput("Controllers cannot be banned".to_string()));
+    }
+    check_length("Reason", &reason, 256)?;
+    let ban = Ban { banned_at: time(), banned_by: ic_cdk::caller(), reason };
+    BANNED_STABLE.with(|map| map.borrow_mut().insert(user, ban));
+    Ok(())
+}
+
+fn prompt_bytes(messages: &[ChatMessage]) -> u64 {
+    messages
+        .iter()
+        .map(|message| match message {
+            ChatMessage::System { content } | ChatMessage::User { content } | ChatMessage::Tool { content, .. } => content.len(),
+            ChatMessage::Assistant(AssistantMessage { content, .. }) => content.as_ref().map_or(0, String::len),
+        } as u64)
+        .sum()
+}
+
+fn usage_day_range(user: Principal, from_day: u64, to_day: u64) -> std::ops::RangeInclusive<UsageDayKey> {
+    ((user, from_day), [0u8; 32])..=((user, to_day), [u8::MAX; 32])
+}
+
+fn update_daily_usage(user: Principal, day: u64, model: &str, update: impl FnOnce(&mut UsageStats)) {
+    let key = ((user, day), string_to_fixed_bytes::<32>(model));
+    USAGE_DAILY_STABLE.with(|map| {
+        let mut map = map.borrow_mut();
+        let mut stats = map.get(&key).unwrap_or_default();
+        update(&mut stats);
+        map.insert(key, stats);
+    });
+}
+
+/// Zapisuje wywołanie jako nieudane jeszcze przed await; sukces poprawia wpis później.
+fn record_usage_start(call: LlmCall, model: &str, prompt_bytes: u64, now: u64) -> u64 {
+    let user = call.user;
+    let seq = USAGE_SEQ_STABLE.with(|map| {
+        let mut map = map.borrow_mut();
+        let seq = map.get(&user).unwrap_or(0) + 1;
+        map.insert(user, seq);
+        seq
+    });
+    let oldest_kept = (seq + 1).saturating_sub(get_setting(Setting::UsageLedgerMaxEntries).max(1));
+    USAGE_LEDGER_STABLE.with(|map| {
+        let mut map = map.borrow_mut();
+        let entry = StoredUsage {
+            timestamp: now,
+            model: model.to_string(),
+            endpoint: call.endpoint.to_string(),
+            prompt_bytes,
+            response_bytes: 0,
+            success: false,
+        };
+        map.insert((user, seq), entry);
+        remove_range_batch(&mut map, (user, 0)..=(user, oldest_kept.saturating_sub(1)), usize::MAX);
+    });
+    update_daily_usage(user, now / NANOS_PER_DAY, model, |stats| {
+        stats.calls += 1;
+        stats.failures += 1;
+        stats.prompt_bytes += prompt_bytes;
+    });
+    seq
+}
+
+fn record_usage_success(user: Principal, seq: u64, model: &str, response_bytes: u64, started_at: u64) {
+    USAGE_LEDGER_STABLE.with(|map| {
+        let mut map = map.borrow_mut();
+        if let Some(mut entry) = map.get(&(user, seq)) {
+            entry.response_bytes = response_bytes;
+            entry.success = true;
+            map.insert((user, seq), entry);
+        }
+    });
+    update_daily_usage(user, started_at / NANOS_PER_DAY, model, |stats| {
+        stats.failures = stats.failures.saturating_sub(1);
+        stats.response_bytes += response_bytes;
+    });
+}
+
+fn usage_report(user: Principal, range: &TimeRange) -> UsageReport {
+    let daily = if range.to > range.from {
+        USAGE_DAILY_STABLE.with(|map| {
+            map.borrow()
+                .range(usage_day_range(user, range.from / NANOS_PER_DAY, (range.to - 1) / NANOS_PER_DAY))
+                .map(|entry| {
+                    let ((_, day), model) = *entry.key();
+                    DailyUsage { day, model: fixed_bytes_to_string(&model), usage: entry.value() }
+                })
+                .collect()
         })
     } else {
-        // przenieś z archiwum z powrotem do normalnej mapy
-        USER_ARCHIVE_STABLE.with(|archive| {
-            let mut archive = archive.borrow_mut();
-            if let Some((name, msg_count)) = archive.remove(&(user, chat_id)) {
-                USER_CHATS_STABLE.with(|map| {
-                    map.borrow_mut().insert((user, chat_id), (name, msg_count));
-                });
-                true
-            } else {
-                false
+        Vec::new()
+    };
+    let entries = USAGE_LEDGER_STABLE.with(|map| {
+        map.borrow()
+            .range((user, 0)..=(user, u64::MAX))
+            .filter_map(|entry| {
+                let usage = entry.value();
+                (usage.timestamp >= range.from && usage.timestamp < range.to).then(|| UsageEntry {
+                    seq: entry.key().1,
+                    timestamp: usage.timestamp,
+                    model: usage.model,
+                    endpoint: usage.endpoint,
+                    prompt_bytes: usage.prompt_bytes,
+                    response_bytes: usage.response_bytes,
+                    success: usage.success,
+                })
+            })
+            .collect()
+    });
+    UsageReport { daily, entries }
+}
+
+/// Zużycie w zakresie zsumowane per użytkownik i model, porcjami po użytkownikach.
+fn usage_rollup(range: &TimeRange, after: Option<Principal>, limit: u32) -> UsageRollup {
+    let limit = limit.clamp(1, ADMIN_MAX_PAGE) as usize;
+    let (from_day, to_day) = (range.from / NANOS_PER_DAY, range.to.saturating_sub(1) / NANOS_PER_DAY);
+    let start = match after {
+        Some(after) => Bound::Excluded(((after, u64::MAX), [u8::MAX; 32])),
+        None => Bound::Unbounded,
+    };
+    let mut users: Vec<UserUsage> = Vec::new();
+    let mut next = None;
+    USAGE_DAILY_STABLE.with(|map| {
+        for entry in map.borrow().range((start, Bound::Unbounded)) {
+            let ((user, day), model) = *entry.key();
+            if users.last().is_none_or(|last| last.user != user) {
+                if users.len() == limit {
+                    next = users.last().map(|last| last.user);
+                    break;
+                }
+                users.push(UserUsage { user, models: Vec::new() });
             }
-        })
+            if range.to <= range.from || day < from_day || day > to_day {
+                continue;
+            }
+            let model = fixed_bytes_to_string(&model);
+            let models = &mut users.last_mut().unwrap().models;
+            match models.iter_mut().find(|m| m.model == model) {
+                Some(existing) => existing.usage.add(&entry.value()),
+                None => models.push(ModelUsage { model, usage: entry.value() }),
+            }
+        }
+    });
+    users.retain(|u| !u.models.is_empty());
+    UsageRollup { users, next }
+}
+
+fn list_uploads_for_user(user: Principal) -> Vec<UploadStatus> {
+    UPLOADS_STABLE.with(|map| {
+        map.borrow()
+            .range(user_chat_range(user))
+            .map(|entry| upload_status(entry.key().1, entry.value()))
+            .collect()
+    })
+}
+
+/// Usuwa uploady bez nowego fragmentu dłużej niż `UploadTtlSecs`, najwyżej `limit` na wywołanie.
+fn expire_uploads(now: u64, limit: usize) -> u32 {
+    let ttl = get_setting(Setting::UploadTtlSecs).saturating_mul(1_000_000_000);
+    let expired: Vec<(Principal, [u8; 16])> = UPLOADS_STABLE.with(|map| {
+        map.borrow()
+            .iter()
+            .filter(|entry| now.saturating_sub(entry.value().updated_at) >= ttl)
+            .map(|entry| *entry.key())
+            .take(limit)
+            .collect()
+    });
+    expired
+        .into_iter()
+        .filter(|(user, upload_id)| remove_upload(*user, *upload_id))
+        .count() as u32
+}
+
+/// Treść do pobrania jako (typ, bajty).
+fn download_content(user: Principal, source: &DownloadSource) -> Result<(String, Vec<u8>), ApiError> {
+    match source {
+        DownloadSource::Image { chat_id, msg_id } => {
+            let image = CHAT_IMAGES_STABLE
+                .with(|map| map.borrow().get(&((user, *chat_id), *msg_id)))
+                .ok_or_else(|| ApiError::NotFound("Image not found".to_string()))?;
+            Ok(("text/plain; charset=utf-8".to_string(), image.data))
+        }
+        DownloadSource::Chat { chat_id } => {
+            if !chat_id_taken(user, *chat_id) {
+                return Err(ApiError::NotFound("Chat not found".to_string()));
+            }
+            let messages: Vec<TypedMessage> = CHAT_MESSAGES_STABLE.with(|map| {
+                map.borrow()
+                    .range(chat_msg_range(user, *chat_id))
+                    .filter_map(|entry| typed_message(entry.key(), &entry.value()))
+                    .collect()
+            });
+            let bytes = candid::encode_one(messages).map_err(|e| ApiError::Internal(e.to_string()))?;
+            Ok(("application/candid; type=vec TypedMessage".to_string(), bytes))
+        }
+        // eksport może być dowolnie duży, więc budują go timery (`process_export_builds`)
+        DownloadSource::Export => Err(ApiError::Internal("Export is built incrementally".to_string())),
+    }
+}
+
+fn download_handle(handle: [u8; 16], download: StoredDownload) -> DownloadHandle {
+    let ttl = get_setting(Setting::DownloadTtlSecs).saturating_mul(1_000_000_000);
+    DownloadHandle {
+        handle,
+        source: download.source,
+        content_type: download.content_type,
+        total_size: download.total_size,
+        sha256: download.sha256,
+        chunk_size: DOWNLOAD_CHUNK_SIZE as u32,
+        chunk_count: download.chunk_count,
+        expires_at: download.created_at.saturating_add(ttl),
+        ready: download.build.is_none(),
+    }
+}
+
+/// Robi migawkę treści, więc kolejne fragmenty są spójne nawet gdy czat się zmienia.
+fn open_download_stable(user: Principal, handle: [u8; 16], source: DownloadSource) -> Result<DownloadHandle, ApiError> {
+    let open = DOWNLOADS_STABLE.with(|map| map.borrow().keys_range(user_chat_range(user)).count());
+    if open >= MAX_OPEN_DOWNLOADS {
+        return Err(ApiError::Busy(format!("At most {} downloads can be open at once", MAX_OPEN_DOWNLOADS)));
+    }
+    if let DownloadSource::Export = source {
+        let download = StoredDownload {
+            source,
+            content_type: EXPORT_CONTENT_TYPE.to_string(),
+            total_size: 0,
+            sha256: [0u8; 32],
+            chunk_count: 0,
+            created_at: time(),
+            build: Some(ExportBuild { cursor: None }),
+        };
+        DOWNLOADS_STABLE.with(|map| map.borrow_mut().insert((user, handle), download.clone()));
+        EXPORT_HASHERS.with(|hashers| hashers.borrow_mut().insert((user, handle), Sha256::new()));
+        schedule_export_builds();
+        return Ok(download_handle(handle, download));
+    }
+    let (content_type, content) = download_content(user, &source)?;
+    let mut chunk_count = 0;
+    DOWNLOAD_CHUNKS_STABLE.with(|map| {
+        let mut map = map.borrow_mut();
+        for chunk in content.chunks(DOWNLOAD_CHUNK_SIZE) {
+            map.insert(((user, handle), chunk_count), chunk.to_vec());
+            chunk_count += 1;
+        }
+    });
+    let download = StoredDownload {
+        source,
+        content_type,
+        total_size: content.len() as u64,
+        sha256: sha256(&content),
+        chunk_count,
+        created_at: time(),
+        build: None,
+    };
+    DOWNLOADS_STABLE.with(|map| map.borrow_mut().insert((user, handle), download.clone()));
+    Ok(download_handle(handle, download))
+}
+
+/// Dokleja bajty na koniec treści pobierania, dopełniając ostatni niepełny fragment.
+fn append_download_bytes(user: Principal, handle: [u8; 16], download: &mut StoredDownload, mut bytes: &[u8]) {
+    DOWNLOAD_CHUNKS_STABLE.with(|map| {
+        let mut map = map.borrow_mut();
+        while !bytes.is_empty() {
+            let partial = !download.total_size.is_multiple_of(DOWNLOAD_CHUNK_SIZE as u64);
+            let index = if partial { download.chunk_count - 1 } else { download.chunk_count };
+            let mut chunk = if partial { map.get(&((user, handle), index)).unwrap_or_default() } else { Vec::new() };
+            let take = (DOWNLOAD_CHUNK_SIZE - chunk.len()).min(bytes.len());
+            chunk.extend_from_slice(&bytes[..take]);
+            map.insert(((user, handle), index), chunk);
+            if !partial {
+                download.chunk_count += 1;
+            }
+            download.total_size += take as u64;
+            bytes = &bytes[take..];
+        }
+    });
+}
+
+/// Dopisuje do eksportu jedną porcję rekordów; zwraca true, gdy eksport jest kompletny.
+fn export_build_step(user: Principal, handle: [u8; 16]) -> bool {
+    let Some(mut download) = DOWNLOADS_STABLE.with(|map| map.borrow().get(&(user, handle))) else {
+        return true;
+    };
+    let Some(mut build) = download.build.take() else {
+        return true;
+    };
+    // stan skrótu żyje na stercie; po upgrade eksport jest budowany od początku
+    if EXPORT_HASHERS.with(|hashers| !hashers.borrow().contains_key(&(user, handle))) {
+        DOWNLOAD_CHUNKS_STABLE.with(|map| {
+            remove_range_batch(&mut map.borrow_mut(), ((user, handle), 0)..=((user, handle), u32::MAX), usize::MAX)
+        });
+        download.total_size = 0;
+        download.chunk_count = 0;
+        build.cursor = None;
+        EXPORT_HASHERS.with(|hashers| hashers.borrow_mut().insert((user, handle), Sha256::new()));
+    }
+
+    let chunk = export_user_data(user, build.cursor, EXPORT_MAX_RECORDS);
+    let page = candid::encode_one(&chunk.records).unwrap_or_default();
+    let mut frame = (page.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&page);
+    EXPORT_HASHERS.with(|hashers| {
+        if let Some(hasher) = hashers.borrow_mut().get_mut(&(user, handle)) {
+            hasher.update(&frame);
+        }
+    });
+    append_download_bytes(user, handle, &mut download, &frame);
+
+    download.build = chunk.next.map(|cursor| ExportBuild { cursor: Some(cursor) });
+    if download.build.is_none() {
+        if let Some(hasher) = EXPORT_HASHERS.with(|hashers| hashers.borrow_mut().remove(&(user, handle))) {
+            download.sha256 = hasher.finalize().into();
+        }
     }
+    let done = download.build.is_none();
+    DOWNLOADS_STABLE.with(|map| map.borrow_mut().insert((user, handle), download));
+    done
 }
 
-fn option_f64_to_bytes(opt: Option<u64>) -> [u8; 9] {
-    let mut bytes = [0u8; 9];
-    match opt {
-        None => bytes[0] = 0,
-        Some(val) => {
-            bytes[0] = 1;
-            bytes[1..9].copy_from_slice(&val.to_le_bytes());
+/// Buduje oczekujące eksporty porcjami, dopóki starcza budżetu instrukcji.
+/// Zwraca true, jeśli została jeszcze praca na kolejne uruchomienie.
+fn process_export_builds() -> bool {
+    let pending: Vec<(Principal, [u8; 16])> = DOWNLOADS_STABLE.with(|map| {
+        map.borrow()
+            .iter()
+            .filter(|entry| entry.value().build.is_some())
+            .map(|entry| *entry.key())
+            .collect()
+    });
+    for (user, handle) in pending {
+        loop {
+            if ic_cdk::api::instruction_counter() > DELETE_INSTRUCTION_BUDGET {
+                return true;
+            }
+            if export_build_step(user, handle) {
+                break;
+            }
         }
     }
-    bytes
+    false
 }
 
-fn bytes_to_option_f64(bytes: &[u8; 9]) -> Option<u64> {
-    match bytes[0] {
-        0 => None,
-        1 => Some(u64::from_le_bytes(bytes[1..9].try_into().unwrap())),
-        _ => None, // albo panic!("Invalid Option encoding")
+fn schedule_export_builds() {
+    let pending = DOWNLOADS_STABLE.with(|map| map.borrow().iter().any(|entry| entry.value().build.is_some()));
+    if pending {
+        ic_cdk_timers::set_timer(Duration::ZERO, || {
+            if process_export_builds() {
+                schedule_export_builds();
+            }
+        });
     }
 }
-fn string_to_bytes(s: &str) -> Vec<u8> {
-    s.as_bytes().to_vec()
+
+fn get_download_stable(user: Principal, handle: [u8; 16]) -> Result<DownloadHandle, ApiError> {
+    DOWNLOADS_STABLE
+        .with(|map| map.borrow().get(&(user, handle)))
+        .map(|download| download_handle(handle, download))
+        .ok_or_else(|| ApiError::NotFound("Download not found".to_string()))
 }
-fn string_to_fixed_bytes<const N: usize>(s: &str) -> [u8; N] {
-    let mut arr = [0u8; N];
-    let bytes = s.as_bytes();
-    let len = bytes.len().min(N);
-    arr[..len].copy_from_slice(&bytes[..len]);
-    arr
+
+fn get_download_chunk_stable(user: Principal, handle: [u8; 16], index: u32) -> Result<Vec<u8>, ApiError> {
+    let download = DOWNLOADS_STABLE
+        .with(|map| map.borrow().get(&(user, handle)))
+        .ok_or_else(|| ApiError::NotFound("Download not found".to_string()))?;
+    if download.build.is_some() {
+        return Err(ApiError::Busy("Export is still being prepared".to_string()));
+    }
+    DOWNLOAD_CHUNKS_STABLE
+        .with(|map| map.borrow().get(&((user, handle), index)))
+        .ok_or_else(|| ApiError::NotFound(format!("Chunk {} not found", index)))
 }
 
-/// Zamienia tablicę bajtów na String, ignorując trailing zera.
-fn fixed_bytes_to_string(bytes: &[u8]) -> String {
-    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
-    String::from_utf8(bytes[..len].to_vec()).unwrap_or_default()
+fn close_download_stable(user: Principal, handle: [u8; 16]) -> bool {
+    EXPORT_HASHERS.with(|hashers| hashers.borrow_mut().remove(&(user, handle)));
+    DOWNLOAD_CHUNKS_STABLE.with(|map| {
+        remove_range_batch(&mut map.borrow_mut(), ((user, handle), 0)..=((user, handle), u32::MAX), usize::MAX)
+    });
+    DOWNLOADS_STABLE.with(|map| map.borrow_mut().remove(&(user, handle))).is_some()
 }
 
-fn inc_user_prompt_stable(user: Principal) -> bool {
-    let now = time();
+fn expire_downloads(now: u64, limit: usize) -> u32 {
+    let ttl = get_setting(Setting::DownloadTtlSecs).saturating_mul(1_000_000_000);
+    let expired: Vec<(Principal, [u8; 16])> = DOWNLOADS_STABLE.with(|map| {
+        map.borrow()
+            .iter()
+            .filter(|entry| now.saturating_sub(entry.value().created_at) >= ttl)
+            .map(|entry| *entry.key())
+            .take(limit)
+            .collect()
+    });
+    expired
+        .into_iter()
+        .filter(|(user, handle)| close_download_stable(*user, *handle))
+        .count() as u32
+}
 
-    USER_PROMPTS_STABLE.with(|map_cell| {
-        let mut map = map_cell.borrow_mut();
+fn rename_chat_stable(user: Principal, chat_id: [u8; 16], new_name: String) -> bool {
+    USER_CHATS_STABLE.with(|map| {
+        let mut map = map.borrow_mut();
+        if let Some((_old_name, msg_count)) = map.get(&(user, chat_id.clone())) {
+            map.insert((user, chat_id.clone()), (string_to_fixed_bytes::<64>(&new_name), msg_count));
+            record_change(user, chat_id, ChangeKind::ChatRenamed { name: new_name });
+            true
+        } else {
+            false
+        }
+    })
+}
 
-        // pobierz aktualną wartość lub ustaw domyślną
-        let mut count = 0;
-        let mut blocked_since: Option<u64> = None;
+/// Wejście starych klientów: obraz, gdy width>0 && height>0, a rola to dowolny tekst (np. tag modelu).
+#[allow(clippy::too_many_arguments)]
+fn insert_message_stable(user: Principal, chat_id: [u8; 16], parent: Option<u32>, content: String, role: String, width: u32, height: u32, timestamp: u64) -> Option<u32> {
+    let image = width>0 && height>0;
+    let content = if image { MessageContent::PixelImage { width, height, data: content } } else { MessageContent::Text(content) };
+    insert_stored_message(user, chat_id, parent, role, None, content, timestamp)
+}
 
-        if let Some((c, b)) = map.get(&user) {
-            count = c.clone();
-            blocked_since = bytes_to_option_f64(&b);
-        }
+fn insert_typed_message_stable(user: Principal, chat_id: [u8; 16], parent: Option<u32>, role: MessageRole, content: MessageContent, model: Option<String>, timestamp: u64) -> Option<u32> {
+    // stare widoki czytają rolę z tekstu, więc zapisujemy tam tag modelu albo nazwę roli
+    let role_text = model.clone().unwrap_or_else(|| role.name().to_string());
+    insert_stored_message(user, chat_id, parent, role_text, Some((role, model)), content, timestamp)
+}
 
-        // logika blokady
-        if let Some(block_time) = blocked_since {
-            if now - block_time >= BLOCK_TIME_NANOS {
-                // reset
-                map.insert(user, (1, option_f64_to_bytes(None)));
-                true
-            } else {
-                false
-            }
-        } else {
-            count += 1;
-            if count >= PROMPT_LIMIT {
-                blocked_since = Some(now);
-            }
-            map.insert(user, (count, option_f64_to_bytes(blocked_since)));
-            true
+fn insert_stored_message(user: Principal, chat_id: [u8; 16], parent: Option<u32>, role: String, typed: Option<(MessageRole, Option<String>)>, content: MessageContent, timestamp: u64) -> Option<u32> {
+    let (data, stored_content, image) = match content {
+        MessageContent::Text(text) => (text, StoredContent::Text, None),
+        MessageContent::PixelImage { width, height, data } => (String::new(), StoredContent::PixelImage, Some(StoredImage { width, height, data: string_to_bytes(&data) })),
+        MessageContent::ToolCall { id, name, arguments } => (String::new(), StoredContent::ToolCall { id, name, arguments }, None),
+        MessageContent::ToolResult { call_id, content } => (content, StoredContent::ToolResult { call_id }, None),
+    };
+    USER_CHATS_STABLE.with(|chat_map| {
+        let mut chat_map = chat_map.borrow_mut();
+        let (name, msg_count) = chat_map.get(&(user, chat_id))?;
+        let new_index = msg_count;
+        CHAT_MESSAGES_STABLE.with(|msg_map| {
+            let stable_msg = StoredMessage {
+                //owner: user,
+                role: string_to_fixed_bytes::<32>(&role),
+                data: string_to_bytes(&data),
+                timestamp,
+                image: image.is_some(),
+                parent: Some(parent.unwrap_or(NO_PARENT)),
+                // stare API też dostaje jawny rodzaj, żeby dalsze decyzje nie zależały od tekstu roli
+                kind: Some(match typed {
+                    Some((role, model)) => StoredKind { role, model, content: stored_content },
+                    None => StoredKind { content: stored_content, ..legacy_kind(role.clone(), image.is_some()) },
+                }),
+            };
+            msg_map
+                .borrow_mut()
+                .insert(((user, chat_id), new_index), stable_msg);
+        });
+        if let Some(stored_image) = image {
+            CHAT_IMAGES_STABLE.with(|msg_map| {
+                msg_map
+                    .borrow_mut()
+                    .insert(((user, chat_id), new_index), stored_image);
+            });
         }
+        chat_map.insert((user, chat_id), (name, new_index + 1));
+        CHAT_ACTIVE_LEAF_STABLE.with(|map| map.borrow_mut().insert((user, chat_id), new_index));
+        record_change(user, chat_id, ChangeKind::MessageAdded { msg_id: new_index, parent, role });
+        Some(new_index)
     })
 }
 
-fn set_name_stable(principal: Principal, value: String) {
-    USER_NAMES_STABLE.with(|map| map.borrow_mut().insert(principal, string_to_fixed_bytes::<32>(&value)));
+fn add_chat_message_stable(user: Principal, chat_id: [u8; 16], content: String, role: String, width: u32, height: u32, timestamp: u64) -> Option<u32> {
+    let (_name, msg_count) = USER_CHATS_STABLE.with(|map| map.borrow().get(&(user, chat_id)))?;
+    // nowa wiadomość doklejana jest do aktualnie wybranej gałęzi
+    let parent = get_active_leaf(user, chat_id, msg_count);
+    insert_message_stable(user, chat_id, parent, content, role, width, height, timestamp)
 }
 
-fn get_name_stable(principal: Principal) -> Option<[u8; 32]> {
-    USER_NAMES_STABLE.with(|map| map.borrow().get(&principal))
+fn get_stored_message(user: Principal, chat_id: [u8; 16], msg_id: u32) -> Option<StoredMessage> {
+    CHAT_MESSAGES_STABLE.with(|map| map.borrow().get(&((user, chat_id), msg_id)))
 }
 
-fn get_image(key: ((Principal, [u8; 16]), u32)) -> Option<String> {
-    let image = CHAT_IMAGES_STABLE.with(|image_map| {
-        let image_map = image_map.borrow();
-            image_map.get(&key)
-    });
+fn edit_message_stable(user: Principal, chat_id: [u8; 16], msg_id: u32, new_content: String) -> Result<u32, ApiError> {
+    let stored_message = get_stored_message(user, chat_id, msg_id)
+        .ok_or_else(|| ApiError::NotFound("Message not found".to_string()))?;
+    let kind = stored_kind(&stored_message);
+    if kind.role != MessageRole::User || !matches!(kind.content, StoredContent::Text) {
+        return Err(ApiError::InvalidInput("Only user messages can be edited".to_string()));
+    }
+    let parent = message_parent(msg_id, &stored_message);
+    insert_typed_message_stable(user, chat_id, parent, MessageRole::User, MessageContent::Text(new_content), None, time())
+        .ok_or_else(|| ApiError::NotFound("Chat not found".to_string()))
+}
 
-    let ext_val = if let Some(stored_image) = image {
-        Some(fixed_bytes_to_string(&stored_image.data))
-    } else { 
-        None
-    };
+fn switch_branch_stable(user: Principal, chat_id: [u8; 16], msg_id: u32) -> Result<u32, ApiError> {
+    if !USER_CHATS_STABLE.with(|map| map.borrow().contains_key(&(user, chat_id))) {
+        return Err(ApiError::NotFound("Chat not found".to_string()));
+    }
+    if get_stored_message(user, chat_id, msg_id).is_none() {
+        return Err(ApiError::NotFound("Message not found".to_string()));
+    }
+    let leaf = latest_descendant(user, chat_id, msg_id);
+    CHAT_ACTIVE_LEAF_STABLE.with(|map| map.borrow_mut().insert((user, chat_id), leaf));
+    record_change(user, chat_id, ChangeKind::ActiveBranchChanged { leaf });
+    Ok(leaf)
+}
 
-    ext_val
+// ToolCallArgument nie jest eksportowany z ic_llm, więc FunctionCall składamy przez serde
+fn llm_function_call(name: String, arguments: Vec<ToolArgument>) -> Option<FunctionCall> {
+    serde_json::from_value(serde_json::json!({ "name": name, "arguments": arguments })).ok()
 }
 
-fn get_chats_for_user(user: Principal) -> Vec<ChatMeta> {
-    USER_CHATS_STABLE.with(|map_ref| {
-        let map = map_ref.borrow();
-        map.iter()
-            .filter_map(|entry| {
-                let (p, index) = entry.key();
-                let (name, msg_count) = entry.value();
-                if *p == user {
-                    Some(ChatMeta {name: fixed_bytes_to_string(&name), id: index.clone(), msg_len: msg_count })
-                } else {
-                    None
-                }
-            })
-            .collect()
-    })
+/// Historia od korzenia do `leaf` w formacie dla ic_llm (obrazy są pomijane).
+fn llm_context(user: Principal, chat_id: [u8; 16], leaf: Option<u32>) -> Vec<ChatMessage> {
+    let Some(leaf) = leaf else {
+        return Vec::new();
+    };
+    get_path_ids(user, chat_id, leaf)
+        .into_iter()
+        .filter_map(|id| get_stored_message(user, chat_id, id))
+        .filter_map(|stored_message| {
+            let kind = stored_kind(&stored_message);
+            let content = fixed_bytes_to_string(&stored_message.data);
+            match (kind.role, kind.content) {
+                (_, StoredContent::PixelImage) | (MessageRole::Image, _) => None,
+                (_, StoredContent::ToolCall { id, name, arguments }) => Some(ChatMessage::Assistant(AssistantMessage {
+                    content: None,
+                    tool_calls: vec![ToolCall { id, function: llm_function_call(name, arguments)? }],
+                })),
+                (_, StoredContent::ToolResult { call_id }) => Some(ChatMessage::Tool { content, tool_call_id: call_id }),
+                (MessageRole::User, _) => Some(ChatMessage::User { content }),
+                (MessageRole::System, _) => Some(ChatMessage::System { content }),
+                _ => Some(ChatMessage::Assistant(AssistantMessage { content: Some(content), tool_calls: vec![] })),
+            }
+        })
+        .collect()
 }
 
-fn get_archives_for_user(user: Principal) -> Vec<ChatMeta> {
-    USER_ARCHIVE_STABLE.with(|map_ref| {
-        let map = map_ref.borrow();
-        map.iter()
-            .filter_map(|entry| {
-                let (p, index) = entry.key();
-                let (name, msg_count) = entry.value();
-                if *p == user {
-                    Some(ChatMeta {name: fixed_bytes_to_string(&name), id: index.clone(), msg_len: msg_count })
-                } else {
-                    None
-                }
-            })
-            .collect()
+/// Dokłada nową kandydatkę do listy; przy pierwszym wywołaniu lista startuje od obecnej treści.
+fn add_candidate_stable(user: Principal, chat_id: [u8; 16], msg_id: u32, role: String, content: String) -> Result<u32, ApiError> {
+    let key = ((user, chat_id), msg_id);
+    let stored_message = get_stored_message(user, chat_id, msg_id)
+        .ok_or_else(|| ApiError::NotFound("Message not found".to_string()))?;
+    CHAT_CANDIDATES_STABLE.with(|map| {
+        let mut map = map.borrow_mut();
+        let mut stored_candidates = map.get(&key).unwrap_or_else(|| StoredCandidates {
+            candidates: vec![StoredCandidate {
+                role: stored_message.role,
+                data: stored_message.data.clone(),
+                timestamp: stored_message.timestamp,
+            }],
+            selected: 0,
+        });
+        stored_candidates.candidates.push(StoredCandidate {
+            role: string_to_fixed_bytes::<32>(&role),
+            data: string_to_bytes(&content),
+            timestamp: time(),
+        });
+        let index = stored_candidates.candidates.len() as u32 - 1;
+        map.insert(key, stored_candidates);
+        Ok(index)
+    })
+    .inspect(|candidate| {
+        record_change(user, chat_id, ChangeKind::CandidateAdded { msg_id, candidate: *candidate });
     })
 }
 
-fn get_all_images_for_user(user: Principal) -> ChatInfo {
-    let mut info = ChatInfo { messages: Vec::new() };
+fn select_candidate_stable(user: Principal, chat_id: [u8; 16], msg_id: u32, candidate: u32) -> Result<(), ApiError> {
+    let key = ((user, chat_id), msg_id);
+    let mut stored_message = get_stored_message(user, chat_id, msg_id)
+        .ok_or_else(|| ApiError::NotFound("Message not found".to_string()))?;
+    let mut stored_candidates = CHAT_CANDIDATES_STABLE
+        .with(|map| map.borrow().get(&key))
+        .ok_or_else(|| ApiError::NotFound("Message has no candidates".to_string()))?;
+    let chosen = stored_candidates
+        .candidates
+        .get(candidate as usize)
+        .ok_or_else(|| ApiError::NotFound("Candidate not found".to_string()))?;
 
-    USER_CHATS_STABLE.with(|chat_map_ref| {
-        let chat_map = chat_map_ref.borrow();
+    // kandydatki to zawsze tekstowe odpowiedzi; rola kandydatki to tag modelu
+    let role_text = fixed_bytes_to_string(&chosen.role);
+    let model = (role_text != MessageRole::Assistant.name()).then_some(role_text);
+    stored_message.role = chosen.role;
+    stored_message.data = chosen.data.clone();
+    stored_message.timestamp = chosen.timestamp;
+    stored_message.kind = Some(StoredKind { role: MessageRole::Assistant, model, content: StoredContent::Text });
+    stored_candidates.selected = candidate;
 
-        for entry in chat_map.iter() {
-            let (p, chat_id) = entry.key();
-            let (_name, msg_count) = entry.value();
+    CHAT_MESSAGES_STABLE.with(|map| map.borrow_mut().insert(key, stored_message));
+    CHAT_CANDIDATES_STABLE.with(|map| map.borrow_mut().insert(key, stored_candidates));
+    record_change(user, chat_id, ChangeKind::CandidateSelected { msg_id, candidate });
+    Ok(())
+}
 
-            if *p != user {
-                continue;
-            }
+#[derive(Clone, Copy, PartialEq)]
+enum Access {
+    Public,
+    // każdy zalogowany (nieanonimowy) wywołujący
+    User,
+    Controller,
+}
 
-            // przechodzimy po wszystkich wiadomościach w czacie
-            for msg_id in 0..msg_count {
-                let key = ((user, chat_id.clone()), msg_id);
+/// Górna granica rozmiaru argumentów; Text i Large rosną razem z limitami walidacji.
+#[derive(Clone, Copy)]
+enum ArgLimit {
+    Small,
+    Text,
+    Large,
+    Chunk,
+}
 
-                CHAT_IMAGES_STABLE.with(|image_map_ref| {
-                    let image_map = image_map_ref.borrow();
+const ARG_OVERHEAD_BYTES: u64 = 4 * 1024;
 
-                    if let Some(stored_image) = image_map.get(&key) {
-                        // budujemy ChatMessageIC dla obrazu
-                        let msg = ChatMessageIC {
-                            role: "image".to_string(), // możesz tu wstawić np. "assistant" jeśli chcesz
-                            content: fixed_bytes_to_string(&stored_image.data),
-                            etc: (0, stored_image.width, stored_image.height), // timestamp = 0, szer./wys. z obrazu
-                        };
-                        info.messages.push(msg);
-                    }
-                });
-            }
-        }
-    });
+struct MethodInfo {
+    name: &'static str,
+    access: Access,
+    args: ArgLimit,
+}
 
-    info
+impl MethodInfo {
+    fn max_arg_bytes(&self) -> u64 {
+        match self.args {
+            ArgLimit::Small => ARG_OVERHEAD_BYTES,
+            ArgLimit::Text => get_setting(Setting::MaxMessageBytes) + ARG_OVERHEAD_BYTES,
+            ArgLimit::Large => get_setting(Setting::MaxImageDataBytes) + ARG_OVERHEAD_BYTES,
+            ArgLimit::Chunk => UPLOAD_CHUNK_MAX as u64 + ARG_OVERHEAD_BYTES,
+        }
+    }
 }
 
-fn get_msgs_for_user(user: Principal, chat_id: [u8; 16], msg_count: u32) -> ChatInfo {
-    CHAT_MESSAGES_STABLE.with(|map_ref| {
-        let map = map_ref.borrow();
-        let mut info = ChatInfo { messages: Vec::new() };
+/// Wszystkie metody canistra; ingress do metod spoza listy jest odrzucany w `inspect_message`.
+const METHODS: &[MethodInfo] = &[
+    MethodInfo { name: "askaidraw", access: Access::Public, args: ArgLimit::Large },
+    MethodInfo { name: "chat", access: Access::Public, args: ArgLimit::Large },
+    MethodInfo { name: "chat_turn", access: Access::User, args: ArgLimit::Text },
+    MethodInfo { name: "regenerate_reply", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "create_new_chat", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "add_chat_message", access: Access::User, args: ArgLimit::Large },
+    MethodInfo { name: "add_message", access: Access::User, args: ArgLimit::Large },
+    MethodInfo { name: "get_messages", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "get_chat_history", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "delete_chat", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "rename_chat", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "update_image", access: Access::User, args: ArgLimit::Large },
+    MethodInfo { name: "list_chats", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "set_user_name", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "get_user_name", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "try_increment_user_prompt", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "archive_chat", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "get_all_images", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "regenerate", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "select_candidate", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "fork_chat", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "edit_message", access: Access::User, args: ArgLimit::Text },
+    MethodInfo { name: "switch_branch", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "get_active_path", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "get_changes_since", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "list_trash", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "restore_chat", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "purge_now", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "begin_upload", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "upload_chunk", access: Access::User, args: ArgLimit::Chunk },
+    MethodInfo { name: "commit_upload", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "abort_upload", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "list_uploads", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "open_download", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "get_download", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "get_download_chunk", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "close_download", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "status", access: Access::Public, args: ArgLimit::Small },
+    MethodInfo { name: "http_request", access: Access::Public, args: ArgLimit::Small },
+    MethodInfo { name: "http_request_update", access: Access::Public, args: ArgLimit::Large },
+    MethodInfo { name: "reserve_prompt", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "get_my_tier", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "list_tiers", access: Access::Public, args: ArgLimit::Small },
+    MethodInfo { name: "buy_tier", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "get_quota_status", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "create_api_key", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "list_api_keys", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "revoke_api_key", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "chat_turn_with_key", access: Access::Public, args: ArgLimit::Text },
+    MethodInfo { name: "draw_with_key", access: Access::Public, args: ArgLimit::Large },
+    MethodInfo { name: "get_messages_with_key", access: Access::Public, args: ArgLimit::Small },
+    MethodInfo { name: "create_share_link", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "create_image_token", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "list_share_links", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "revoke_share_link", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "set_setting", access: Access::Controller, args: ArgLimit::Small },
+    MethodInfo { name: "verify_integrity", access: Access::Controller, args: ArgLimit::Small },
+    MethodInfo { name: "admin_list_users", access: Access::Controller, args: ArgLimit::Small },
+    MethodInfo { name: "admin_reset_prompt_quota", access: Access::Controller, args: ArgLimit::Small },
+    MethodInfo { name: "admin_set_prompt_limit", access: Access::Controller, args: ArgLimit::Small },
+    MethodInfo { name: "admin_ban_user", access: Access::Controller, args: ArgLimit::Small },
+    MethodInfo { name: "admin_unban_user", access: Access::Controller, args: ArgLimit::Small },
+    MethodInfo { name: "admin_delete_user_data", access: Access::Controller, args: ArgLimit::Small },
+    MethodInfo { name: "admin_set_user_tier", access: Access::Controller, args: ArgLimit::Small },
+    MethodInfo { name: "admin_set_tier_limits", access: Access::Controller, args: ArgLimit::Small },
+    MethodInfo { name: "admin_usage_rollup", access: Access::Controller, args: ArgLimit::Small },
+    MethodInfo { name: "get_my_usage", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "get_setting_value", access: Access::Public, args: ArgLimit::Small },
+    MethodInfo { name: "export_my_data", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "delete_my_account", access: Access::User, args: ArgLimit::Small },
+    MethodInfo { name: "get_account_deletion_progress", access: Access::User, args: ArgLimit::Small },
+];
 
-        for i in 0..msg_count {
-            let key = ((user, chat_id.clone()), i);
-            if let Some(stored_message) = map.get(&key) {
-                let image = CHAT_IMAGES_STABLE.with(|image_map| {
-                    let image_map = image_map.borrow();
-                    image_map.get(&key)
-                });
-                let mut etc = (stored_message.timestamp, 0, 0);
-                if stored_message.image {
-                    if let Some(stable_image) = image {
-                        etc.1 = stable_image.width;
-                        etc.2 = stable_image.height;
-                        info.messages.push(ChatMessageIC { role: fixed_bytes_to_string(&stored_message.role), content: fixed_bytes_to_string(&stable_image.data), etc });
-                    }
-                } else {
-                    info.messages.push(ChatMessageIC { role: fixed_bytes_to_string(&stored_message.role), content: fixed_bytes_to_string(&stored_message.data), etc });
-                }
-            }
+fn method_info(method: &str) -> Option<&'static MethodInfo> {
+    METHODS.iter().find(|m| m.name == method)
+}
+
+/// Sprawdza wywołującego według METHODS; to samo sprawdzenie robi `inspect_message` dla ingressu.
+fn authorize(method: &str) -> Result<(), ApiError> {
+    let caller = ic_cdk::caller();
+    let info = method_info(method).ok_or_else(|| ApiError::Unauthorized(format!("Unknown method {}", method)))?;
+    if info.access != Access::Controller && is_banned(caller) {
+        return Err(ApiError::Unauthorized(format!("Caller is banned from {}", method)));
+    }
+    match info.access {
+        Access::Public => Ok(()),
+        Access::User if caller == Principal::anonymous() => {
+            Err(ApiError::Unauthorized(format!("{} requires a signed-in caller", method)))
         }
+        Access::User => Ok(()),
+        Access::Controller if ic_cdk::api::is_controller(&caller) => Ok(()),
+        Access::Controller => Err(ApiError::Unauthorized(format!("Only controllers can call {}", method))),
+    }
+}
 
-        info
-    })
+/// `authorize` dla metod działających na danych `user`: wywołujący musi być tym użytkownikiem
+/// (albo kontrolerem), a zbanowanego użytkownika nie obsługujemy z żadnej tożsamości.
+fn authorize_user(method: &str, user: Principal) -> Result<(), ApiError> {
+    authorize(method)?;
+    let caller = ic_cdk::caller();
+    if caller != user && !ic_cdk::api::is_controller(&caller) {
+        return Err(ApiError::Unauthorized(format!("{} can only act on the caller's own data", method)));
+    }
+    if is_banned(user) {
+        return Err(ApiError::Unauthorized(format!("User is banned from {}", method)));
+    }
+    Ok(())
 }
 
-fn create_new_chat_stable(user: Principal, chat_id: [u8; 16], name: String) {
-    USER_CHATS_STABLE.with(|map| {
-        map.borrow_mut().insert((user, chat_id), (string_to_fixed_bytes::<64>(&name), 0));
-    });
+fn authorize_user_or_reject(method: &str, user: Principal) {
+    if let Err(e) = authorize_user(method, user) {
+        ic_cdk::trap(&format!("{:?}", e));
+    }
 }
 
-fn delete_chat_stable(user: Principal, chat_id: [u8; 16]) -> bool {
-    let removed = USER_CHATS_STABLE.with(|map| map.borrow_mut().remove(&(user, chat_id.clone())));
-    if removed.is_some() {
-        CHAT_MESSAGES_STABLE.with(|map| {
-            let mut map = map.borrow_mut();
-            let keys_to_remove: Vec<_> = map
-                .iter()
-                .filter_map(|entry| {
-                    let ((p, c), idx) = entry.key();
-                    if *p == user && *c == chat_id {
-                        let storable_message = entry.value();
-                        if storable_message.image {
-                            CHAT_IMAGES_STABLE.with(|image_map| {
-                                let mut image_map = image_map.borrow_mut();
-                                image_map.remove(&((user, chat_id.clone()), *idx));
-                            })
-                        }
-                        Some(((user, chat_id.clone()), *idx))
-                    } else {
-                        None
-                    }
-                })
-                .collect();
+/// `authorize` dla metod, które nie zwracają `Result`: odmowa odrzuca wywołanie tak jak guard.
+fn authorize_or_reject(method: &str) {
+    if let Err(e) = authorize(method) {
+        ic_cdk::trap(&format!("{:?}", e));
+    }
+}
+
+#[inspect_message]
+fn inspect_message() {
+    let method = ic_cdk::api::call::method_name();
+    let Some(info) = method_info(&method) else {
+        return;
+    };
+    if authorize(&method).is_err() {
+        return;
+    }
+    if ic_cdk::api::call::arg_data_raw_size() as u64 > info.max_arg_bytes() {
+        return;
+    }
+    ic_cdk::api::call::accept_message();
+}
 
-            for key in keys_to_remove {
-                map.remove(&key);
+/// Kolejność magazynów z wersji 1, w której postęp usuwania był pozycją w tej tablicy.
+const LEGACY_DELETION_ORDER: [DataStore; 22] = [
+    DataStore::Messages,
+    DataStore::Images,
+    DataStore::Candidates,
+    DataStore::ActiveLeaves,
+    DataStore::Origins,
+    DataStore::Versions,
+    DataStore::Chats,
+    DataStore::Archive,
+    DataStore::Trash,
+    DataStore::Prompts,
+    DataStore::Names,
+    DataStore::RequestKeys,
+    DataStore::Changes,
+    DataStore::Uploads,
+    DataStore::Downloads,
+    DataStore::ShareLinks,
+    DataStore::ApiKeys,
+    DataStore::Usage,
+    DataStore::Reservations,
+    DataStore::Tier,
+    DataStore::TrashOrigins,
+    DataStore::PurgeQueue,
+];
+
+/// Podnosi zapisane dane do `SCHEMA_VERSION`, krok po kroku.
+fn migrate_schema() {
+    let mut version = SCHEMA_VERSION_STABLE.with(|cell| *cell.borrow().get()).max(1);
+    if version > SCHEMA_VERSION {
+        ic_cdk::trap(&format!("Stable schema {} is newer than this build ({})", version, SCHEMA_VERSION));
+    }
+    if version == 1 {
+        let pending: Vec<(Principal, (u8, u64, u64))> = ACCOUNT_DELETION_STABLE
+            .with(|map| map.borrow().iter().map(|entry| (*entry.key(), entry.value())).collect());
+        ACCOUNT_DELETION_STABLE.with(|map| {
+            let mut map = map.borrow_mut();
+            for (user, (position, removed, started_at)) in pending {
+                let store = LEGACY_DELETION_ORDER.get(position as usize).copied().unwrap_or(DataStore::Done);
+                map.insert(user, (store.id(), removed, started_at));
             }
         });
-        true
-    } else {
-        false
+        version = 2;
     }
+    if (2..4).contains(&version) {
+        // tylko nowe magazyny i pola opcjonalne
+        version = 4;
+    }
+    SCHEMA_VERSION_STABLE.with(|cell| cell.borrow_mut().set(version));
 }
 
-fn rename_chat_stable(user: Principal, chat_id: [u8; 16], new_name: String) -> bool {
-    USER_CHATS_STABLE.with(|map| {
-        let mut map = map.borrow_mut();
-        if let Some((_old_name, msg_count)) = map.get(&(user, chat_id.clone())) {
-            map.insert((user, chat_id.clone()), (string_to_fixed_bytes::<64>(&new_name), msg_count));
-            true
-        } else {
-            false
-        }
-    })
+#[init]
+fn init() {
+    SCHEMA_VERSION_STABLE.with(|cell| cell.borrow_mut().set(SCHEMA_VERSION));
+    STARTED_AT.with(|t| *t.borrow_mut() = time());
+    start_timers();
 }
 
-fn add_chat_message_stable(user: Principal, chat_id: [u8; 16], content: String, role: String, width: u32, height: u32, timestamp: u64) -> bool {
-    let image = width>0 && height>0;
-    let etc = (timestamp, image);
-    let etc_image = (width, height);
-    USER_CHATS_STABLE.with(|chat_map| {
-        let mut chat_map = chat_map.borrow_mut();
-        if let Some((name, msg_count)) = chat_map.get(&(user, chat_id.clone())) {
-            let new_index = msg_count;
-            CHAT_MESSAGES_STABLE.with(|msg_map| {
-                let stable_msg = StoredMessage {
-                    //owner: user,
-                    role: string_to_fixed_bytes::<32>(&role),
-                    data: string_to_bytes(if etc.1 {""} else {content.as_str()}),
-                    timestamp: etc.0,
-                    image: etc.1,
-                };
-                msg_map
-                    .borrow_mut()
-                    .insert(((user, chat_id.clone()), new_index), stable_msg);
-            });
-            if etc.1 {
-                CHAT_IMAGES_STABLE.with(|msg_map| {
-                    let etc_image = StoredImage {
-                        //owner: user,
-                        width: etc_image.0,
-                        height: etc_image.1,
-                        data: string_to_bytes(content.as_str()),
-                    };
-                    msg_map
-                        .borrow_mut()
-                        .insert(((user, chat_id.clone()), new_index), etc_image);
-                });
-            }
-            chat_map.insert((user, chat_id.clone()), (name.clone(), new_index + 1));
-            true
-        } else {
-            false
-        }
-    })
+#[post_upgrade]
+fn post_upgrade() {
+    migrate_schema();
+    STARTED_AT.with(|t| *t.borrow_mut() = time());
+    start_timers();
 }
 
 /*thread_local! {
@@ -489,6 +5335,8 @@ fn add_chat_message_stable(user: Principal, chat_id: [u8; 16], content: String,
 struct OpenAIRequest {
     model: String,
     messages: Vec<Message>,
+    #[serde(default)]
+    stream: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -497,9 +5345,142 @@ struct Message {
     content: String,
 }
 
-#[update]
-async fn askaidraw(query: String, tag: String, msg_content: String) -> String {
-    let model = match tag.as_str() {
+/// Nazwa modelu z API OpenAI -> tag modelu używany w canistrze.
+fn openai_model_tag(model: &str) -> Option<&'static str> {
+    match model {
+        "Llama3_1_8B" | "llama3.1:8b" | "llama-3.1-8b" => Some("Llama3_1_8B"),
+        "Qwen3_32B" | "qwen3:32b" | "qwen3-32b" => Some("Qwen3_32B"),
+        "Llama4Scout" | "llama4-scout" | "llama-4-scout" => Some("Llama4Scout"),
+        _ => None,
+    }
+}
+
+/// Błąd w formacie OpenAI: `{"error": {"message", "type", "code"}}`.
+fn openai_error(status_code: u16, kind: &str, message: &str) -> HttpResponse {
+    openai_error_code(status_code, kind, None, message)
+}
+
+fn openai_error_code(status_code: u16, kind: &str, code: Option<&str>, message: &str) -> HttpResponse {
+    let body = serde_json::json!({
+        "error": { "message": message, "type": kind, "param": null, "code": code }
+    });
+    http_response(
+        status_code,
+        vec![("Content-Type".to_string(), "application/json".to_string())],
+        body.to_string().into_bytes(),
+    )
+}
+
+fn openai_api_error(error: ApiError) -> HttpResponse {
+    match error {
+        ApiError::InvalidInput(message) => openai_error(400, "invalid_request_error", &message),
+        ApiError::Unauthorized(message) => openai_error(401, "authentication_error", &message),
+        ApiError::NotFound(message) => openai_error(404, "invalid_request_error", &message),
+        ApiError::ModelNotAllowed(message) => openai_error_code(404, "invalid_request_error", Some("model_not_found"), &message),
+        ApiError::Busy(message) => openai_error(429, "rate_limit_error", &message),
+        ApiError::Llm(message) => openai_error(502, "api_error", &message),
+        _ => openai_error(500, "api_error", "Internal error"),
+    }
+}
+
+fn openai_messages(messages: &[Message]) -> Result<Vec<ChatMessage>, ApiError> {
+    if messages.is_empty() {
+        return Err(ApiError::InvalidInput("messages must not be empty".to_string()));
+    }
+    messages
+        .iter()
+        .map(|message| {
+            validate_text("Message", &message.content)?;
+            let content = message.content.clone();
+            match message.role.as_str() {
+                "system" | "developer" => Ok(ChatMessage::System { content }),
+                "user" => Ok(ChatMessage::User { content }),
+                "assistant" => Ok(ChatMessage::Assistant(AssistantMessage { content: Some(content), tool_calls: vec![] })),
+                role => Err(ApiError::InvalidInput(format!("Unsupported role: {}", role))),
+            }
+        })
+        .collect()
+}
+
+/// POST /v1/chat/completions – zgodne z API OpenAI (bez strumieniowania).
+async fn openai_chat_completions(req: &HttpRequest) -> HttpResponse {
+    let Some(token) = bearer_token(req) else {
+        return openai_error(401, "authentication_error", "Missing API key");
+    };
+    let owner = match api_key_owner(token, ApiKeyScope::Chat) {
+        Ok(owner) => owner,
+        Err(e) => return openai_api_error(e),
+    };
+    let request: OpenAIRequest = match serde_json::from_slice(&req.body) {
+        Ok(request) => request,
+        Err(e) => return openai_error(400, "invalid_request_error", &format!("Invalid JSON body: {}", e)),
+    };
+    if request.stream {
+        return openai_error(400, "invalid_request_error", "Streaming is not supported");
+    }
+    let Some(tag) = openai_model_tag(&request.model) else {
+        let message = format!("The model `{}` does not exist", request.model);
+        return openai_error_code(404, "invalid_request_error", Some("model_not_found"), &message);
+    };
+    if let Err(e) = check_model_allowed(owner, &chat_model(tag).to_string()) {
+        return openai_api_error(e);
+    }
+    let messages = match openai_messages(&request.messages) {
+        Ok(messages) => messages,
+        Err(e) => return openai_api_error(e),
+    };
+    let reservation = match record_api_key_use(token) {
+        Ok(key_id) => reserve_prompt_stable(owner, QuotaPool::Chat, Some(key_id)).await,
+        Err(e) => Err(e),
+    };
+    let reservation = match reservation {
+        Ok(reservation) => reservation,
+        Err(e) => return openai_api_error(e),
+    };
+    let call = LlmCall { user: owner, endpoint: "/v1/chat/completions" };
+    let reply = llm_reply(call, tag, messages).await;
+    settle_reservation(owner, reservation.id, reply.is_ok());
+    let content = match reply {
+        Ok(content) => content,
+        Err(e) => return openai_api_error(e),
+    };
+    let id = sha256(&[req.body.as_slice(), &time().to_be_bytes()].concat());
+    let body = serde_json::json!({
+        "id": format!("chatcmpl-{}", hex_encode(&id[..12])),
+        "object": "chat.completion",
+        "created": time() / 1_000_000_000,
+        "model": request.model,
+        "choices": [{
+            "index": 0,
+            "message": Message { role: "assistant".to_string(), content },
+            "finish_reason": "stop",
+        }],
+    });
+    http_response(
+        200,
+        vec![("Content-Type".to_string(), "application/json".to_string())],
+        body.to_string().into_bytes(),
+    )
+}
+
+#[update(guard = "not_banned")]
+async fn askaidraw(query: String, tag: String, msg_content: String, reservation: Option<[u8; 16]>) -> String {
+    authorize_or_reject("askaidraw");
+    // rezerwacje są kluczowane tym samym principalem (`reserve_prompt` wiąże `user` z wywołującym)
+    let user = ic_cdk::caller();
+    if check_model_allowed(user, &draw_model(&tag).to_string()).is_err() {
+        return MODEL_NOT_ALLOWED_REPLY.to_string();
+    }
+    let Ok(id) = claim_reservation(user, reservation, QuotaPool::Drawing).await else {
+        return "ERR".to_string();
+    };
+    let content = draw_step(LlmCall { user, endpoint: "askaidraw" }, query, tag, msg_content).await;
+    settle_reservation(user, id, content != "ERR");
+    content
+}
+
+fn draw_model(tag: &str) -> Model {
+    match tag {
         "Llama4Scout_Image" => {
             Model::Llama4Scout
         }
@@ -509,7 +5490,11 @@ async fn askaidraw(query: String, tag: String, msg_content: String) -> String {
         _ => {
             Model::Llama3_1_8B
         }
-    };
+    }
+}
+
+async fn draw_step(call: LlmCall, query: String, tag: String, msg_content: String) -> String {
+    let model = draw_model(&tag);
 
     //let key = ((user, chat_id), msg_id);
     let image = Some(msg_content);
@@ -538,14 +5523,11 @@ async fn askaidraw(query: String, tag: String, msg_content: String) -> String {
         });
     }
 
-    let builder = ChatBuilder::new(model).with_messages(messages.clone());
-    let response = builder.send().await;
-    response.message.content.unwrap_or("ERR".to_string())
+    send_llm(call, model, messages).await.unwrap_or_else(|_| "ERR".to_string())
 }
 
-#[update]
-async fn chat(prompt: String, tag: String, history: Vec<(String, String)>) -> String {
-    let model = match tag.as_str() {
+fn chat_model(tag: &str) -> Model {
+    match tag {
         "Llama3_1_8B" => {
             Model::Llama3_1_8B
         }
@@ -558,7 +5540,17 @@ async fn chat(prompt: String, tag: String, history: Vec<(String, String)>) -> St
         _ => {
             Model::Llama3_1_8B
         }
-    };
+    }
+}
+
+#[update(guard = "not_banned")]
+async fn chat(prompt: String, tag: String, history: Vec<(String, String)>, reservation: Option<[u8; 16]>) -> String {
+    authorize_or_reject("chat");
+    let user = ic_cdk::caller();
+    let model = chat_model(tag.as_str());
+    if check_model_allowed(user, &model.to_string()).is_err() {
+        return MODEL_NOT_ALLOWED_REPLY.to_string();
+    }
 
     let mut messages = Vec::new();
     for message in &history {
@@ -583,43 +5575,353 @@ async fn chat(prompt: String, tag: String, history: Vec<(String, String)>) -> St
 
     messages.push(ChatMessage::User { content: prompt });
 
-    let builder = ChatBuilder::new(model).with_messages(messages.clone());
-    let response = builder.send().await;
-    response.message.content.unwrap_or("ERR".to_string())
+    let Ok(id) = claim_reservation(user, reservation, QuotaPool::Chat).await else {
+        return "ERR".to_string();
+    };
+    let content = send_llm(LlmCall { user, endpoint: "chat" }, model, messages).await;
+    settle_reservation(user, id, content.is_ok());
+    content.unwrap_or_else(|_| "ERR".to_string())
 }
 
-#[update]
-fn create_new_chat(user: Principal, uid: [u8; 16], name: String) {
-    create_new_chat_stable(user, uid, name);
+/// Wywołanie LLM z licznikami i wpisem w dzienniku zużycia. Porażka jest liczona z góry
+/// i cofana po sukcesie, bo nieudane wywołanie kończy się trapem i kod po await się nie wykona.
+async fn send_llm(call: LlmCall, model: Model, messages: Vec<ChatMessage>) -> Result<String, ApiError> {
+    let label = model.to_string();
+    check_model_allowed(call.user, &label)?;
+    add_counter(Counter::LlmCalls, &label, 1);
+    add_counter(Counter::LlmFailures, &label, 1);
+    let started_at = time();
+    let seq = record_usage_start(call, &label, prompt_bytes(&messages), started_at);
+    LLM_HEALTH.with(|h| h.borrow_mut().last_attempt = Some(time()));
+    let content = ChatBuilder::new(model).with_messages(messages).send().await.message.content;
+    LLM_HEALTH.with(|h| {
+        let mut health = h.borrow_mut();
+        if content.is_some() {
+            health.last_success = Some(time());
+        } else {
+            health.last_failure = Some(time());
+        }
+    });
+    if let Some(content) = &content {
+        add_counter(Counter::LlmFailures, &label, -1);
+        record_usage_success(call.user, seq, &label, content.len() as u64, started_at);
+    }
+    content.ok_or_else(|| ApiError::Llm("ERR".to_string()))
 }
 
-#[update]
-fn add_chat_message(user: Principal, chat_id: [u8; 16], content: String, role: String, width: u32, height: u32, date: u64) {
-    let _ = add_chat_message_stable(user, chat_id, content, role, width, height, date);
+/// Odpowiedź modelu przycięta do limitu długości wiadomości.
+async fn llm_reply(call: LlmCall, tag: &str, messages: Vec<ChatMessage>) -> Result<String, ApiError> {
+    let content = send_llm(call, chat_model(tag), messages).await?;
+    Ok(truncate_utf8(&content, get_setting(Setting::MaxMessageBytes) as usize).to_string())
 }
 
-#[query]
+/// Po await: jeśli klient podał `expected_version`, czat nie mógł się zmienić w trakcie tury.
+fn ensure_chat_unchanged(user: Principal, chat_id: [u8; 16], opts: &Option<WriteOpts>, version_before: u64) -> Result<(), ApiError> {
+    let current = chat_version(user, chat_id);
+    if opts.as_ref().and_then(|o| o.expected_version).is_some() && current != version_before {
+        return Err(ApiError::Conflict { expected: version_before, current });
+    }
+    Ok(())
+}
+
+fn assistant_message(user: Principal, chat_id: [u8; 16], msg_id: u32) -> Result<StoredMessage, ApiError> {
+    let stored_message = get_stored_message(user, chat_id, msg_id)
+        .ok_or_else(|| ApiError::NotFound("Message not found".to_string()))?;
+    let kind = stored_kind(&stored_message);
+    if kind.role != MessageRole::Assistant || !matches!(kind.content, StoredContent::Text) {
+        return Err(ApiError::InvalidInput("Only assistant replies can be regenerated".to_string()));
+    }
+    Ok(stored_message)
+}
+
+/// Pełna tura na czacie: zapisuje prompt, odpytuje model na aktywnej gałęzi i zapisuje odpowiedź.
+/// Zwraca id odpowiedzi. Zużywa prompt z puli `Chat` (podaną rezerwację albo nową, jak `chat`).
+#[update(guard = "not_banned")]
+async fn chat_turn(user: Principal, chat_id: [u8; 16], prompt: String, tag: String, opts: Option<WriteOpts>, reservation: Option<[u8; 16]>) -> Result<u32, ApiError> {
+    authorize_user("chat_turn", user)?;
+    // id rezerwacji nie wchodzi do skrótu: ponowienie może nieść nową, a stara sama wygaśnie
+    let args = args_hash(&(chat_id, &prompt, &tag));
+    if let Some(result) = idempotent_begin(user, "chat_turn", &opts, args) {
+        return result;
+    }
+    let result = async {
+        // limit sprawdzamy przed zapisem promptu
+        let id = claim_reservation(user, reservation, QuotaPool::Chat).await?;
+        let result = run_chat_turn(user, chat_id, prompt, tag, &opts).await;
+        settle_reservation(user, id, result.is_ok());
+        result
+    }.await;
+    idempotent_store(user, "chat_turn", &opts, args, &result);
+    result
+}
+
+async fn run_chat_turn(user: Principal, chat_id: [u8; 16], prompt: String, tag: String, opts: &Option<WriteOpts>) -> Result<u32, ApiError> {
+    validate_text("Prompt", &prompt)?;
+    validate_role_text(&tag)?;
+    check_model_allowed(user, &chat_model(&tag).to_string())?;
+    check_storage(user, prompt.len() as u64)?;
+    let _lock = ChatTurnLock::acquire(user, chat_id)?;
+    let prompt_id = with_chat_version(user, chat_id, opts, || {
+        add_chat_message_stable(user, chat_id, prompt, "user".to_string(), 0, 0, time())
+            .ok_or_else(|| ApiError::NotFound("Chat not found".to_string()))
+    })?;
+    let version_before = chat_version(user, chat_id);
+
+    let call = LlmCall { user, endpoint: "chat_turn" };
+    let content = llm_reply(call, &tag, llm_context(user, chat_id, Some(prompt_id))).await?;
+
+    ensure_chat_unchanged(user, chat_id, opts, version_before)?;
+    let reply_id = insert_typed_message_stable(user, chat_id, Some(prompt_id), MessageRole::Assistant, MessageContent::Text(content), Some(tag), time())
+        .ok_or_else(|| ApiError::NotFound("Chat not found".to_string()))?;
+    bump_chat_version(user, chat_id);
+    Ok(reply_id)
+}
+
+/// `chat_turn` dla botów: właściciel czatu wynika z klucza API (zakres `Chat`).
+#[update(guard = "not_banned")]
+async fn chat_turn_with_key(api_key: String, chat_id: [u8; 16], prompt: String, tag: String, opts: Option<WriteOpts>) -> Result<u32, ApiError> {
+    authorize("chat_turn_with_key")?;
+    let user = api_key_owner(&api_key, ApiKeyScope::Chat)?;
+    let args = args_hash(&(&api_key, chat_id, &prompt, &tag));
+    if let Some(result) = idempotent_begin(user, "chat_turn_with_key", &opts, args) {
+        return result;
+    }
+    let result = async {
+        let key_id = record_api_key_use(&api_key)?;
+        let reservation = reserve_prompt_stable(user, QuotaPool::Chat, Some(key_id)).await?;
+        let result = run_chat_turn(user, chat_id, prompt, tag, &opts).await;
+        settle_reservation(user, reservation.id, result.is_ok());
+        result
+    }.await;
+    idempotent_store(user, "chat_turn_with_key", &opts, args, &result);
+    result
+}
+
+/// Krok rysowania (jak `askaidraw`) rozliczany na klucz API z zakresem `Drawing`.
+#[update(guard = "not_banned")]
+async fn draw_with_key(api_key: String, query: String, tag: String, msg_content: String) -> Result<String, ApiError> {
+    authorize("draw_with_key")?;
+    let user = api_key_owner(&api_key, ApiKeyScope::Drawing)?;
+    validate_text("Prompt", &query)?;
+    check_model_allowed(user, &draw_model(&tag).to_string())?;
+    let key_id = record_api_key_use(&api_key)?;
+    let reservation = reserve_prompt_stable(user, QuotaPool::Drawing, Some(key_id)).await?;
+    let content = draw_step(LlmCall { user, endpoint: "draw_with_key" }, query, tag, msg_content).await;
+    settle_reservation(user, reservation.id, content != "ERR");
+    Ok(content)
+}
+
+/// Odczyt aktywnej ścieżki czatu kluczem API; update, żeby zapisać czas ostatniego użycia.
+#[update(guard = "not_banned")]
+fn get_messages_with_key(api_key: String, chat_id: [u8; 16]) -> Result<Vec<TypedMessage>, ApiError> {
+    authorize("get_messages_with_key")?;
+    let user = api_key_owner(&api_key, ApiKeyScope::ReadOnly)?;
+    if !chat_exists(user, chat_id) {
+        return Err(ApiError::NotFound("Chat not found".to_string()));
+    }
+    record_api_key_use(&api_key)?;
+    Ok(get_typed_messages_stable(user, chat_id))
+}
+
+/// Wspólny przebieg `regenerate`/`regenerate_reply`: rezerwacja z puli `Chat`, blokada czatu, kontrola
+/// wersji i wywołanie LLM na kontekście rodzica `msg_id`. `write` zapisuje odpowiedź (rodzic, treść, tag)
+/// pod tą samą blokadą.
+#[allow(clippy::too_many_arguments)]
+async fn regenerate_with(
+    user: Principal,
+    chat_id: [u8; 16],
+    msg_id: u32,
+    tag: String,
+    opts: Option<WriteOpts>,
+    reservation: Option<[u8; 16]>,
+    endpoint: &'static str,
+    write: impl FnOnce(Option<u32>, String, String) -> Result<u32, ApiError>,
+) -> Result<u32, ApiError> {
+    let args = args_hash(&(chat_id, msg_id, &tag));
+    if let Some(result) = idempotent_begin(user, endpoint, &opts, args) {
+        return result;
+    }
+    let result = async {
+        validate_role_text(&tag)?;
+        check_model_allowed(user, &chat_model(&tag).to_string())?;
+        check_storage(user, 0)?;
+        let reservation_id = claim_reservation(user, reservation, QuotaPool::Chat).await?;
+        let result = async {
+            let _lock = ChatTurnLock::acquire(user, chat_id)?;
+            check_chat_version(user, chat_id, &opts)?;
+            let stored_message = assistant_message(user, chat_id, msg_id)?;
+            let parent = message_parent(msg_id, &stored_message);
+            let version_before = chat_version(user, chat_id);
+
+            let call = LlmCall { user, endpoint };
+            let content = llm_reply(call, &tag, llm_context(user, chat_id, parent)).await?;
+
+            ensure_chat_unchanged(user, chat_id, &opts, version_before)?;
+            check_storage(user, content.len() as u64)?;
+            let id = write(parent, content, tag)?;
+            bump_chat_version(user, chat_id);
+            Ok(id)
+        }.await;
+        settle_reservation(user, reservation_id, result.is_ok());
+        result
+    }.await;
+    idempotent_store(user, endpoint, &opts, args, &result);
+    result
+}
+
+/// Generuje nową odpowiedź jako rodzeństwo `msg_id` (nowa gałąź) i ustawia ją jako aktywną.
+#[update(guard = "not_banned")]
+async fn regenerate_reply(user: Principal, chat_id: [u8; 16], msg_id: u32, tag: String, opts: Option<WriteOpts>, reservation: Option<[u8; 16]>) -> Result<u32, ApiError> {
+    authorize_user("regenerate_reply", user)?;
+    regenerate_with(user, chat_id, msg_id, tag, opts, reservation, "regenerate_reply", |parent, content, tag| {
+        insert_typed_message_stable(user, chat_id, parent, MessageRole::Assistant, MessageContent::Text(content), Some(tag), time())
+            .ok_or_else(|| ApiError::NotFound("Chat not found".to_string()))
+    })
+    .await
+}
+
+#[update(guard = "not_banned")]
+async fn create_new_chat(user: Principal, name: String, opts: Option<WriteOpts>) -> Result<[u8; 16], ApiError> {
+    authorize_user("create_new_chat", user)?;
+    let args = args_hash(&(&name,));
+    if let Some(result) = idempotent_begin(user, "create_new_chat", &opts, args) {
+        return result;
+    }
+    let result = match validate_chat_name(&name).and_then(|_| check_chat_count(user)) {
+        Ok(()) => match new_chat_id(user).await {
+            Ok(chat_id) if create_new_chat_stable(user, chat_id, name) => Ok(chat_id),
+            Ok(_) => Err(ApiError::Internal("Chat id already in use".to_string())),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+    idempotent_store(user, "create_new_chat", &opts, args, &result);
+    result
+}
+
+/// Czas wiadomości nadaje canister (`time()`, w nanosekundach). Zwraca id nowej wiadomości.
+#[update(guard = "not_banned")]
+fn add_chat_message(user: Principal, chat_id: [u8; 16], content: String, role: String, width: u32, height: u32, opts: Option<WriteOpts>) -> Result<u32, ApiError> {
+    authorize_user("add_chat_message", user)?;
+    idempotent(user, "add_chat_message", &opts, args_hash(&(chat_id, &content, &role, width, height)), || {
+        validate_role_text(&role)?;
+        if width > 0 && height > 0 {
+            validate_image(width, height, &content)?;
+            check_tier_image(user, width, height)?;
+        } else {
+            validate_text("Message", &content)?;
+        }
+        check_storage(user, content.len() as u64)?;
+        with_chat_version(user, chat_id, &opts, || {
+            add_chat_message_stable(user, chat_id, content, role, width, height, time())
+                .ok_or_else(|| ApiError::NotFound("Chat not found".to_string()))
+        })
+    })
+}
+
+fn check_message_kind(role: MessageRole, content: &MessageContent) -> Result<(), ApiError> {
+    let valid = match content {
+        MessageContent::Text(_) => role != MessageRole::Image,
+        MessageContent::PixelImage { width, height, .. } => role == MessageRole::Image && *width > 0 && *height > 0,
+        MessageContent::ToolCall { .. } => role == MessageRole::Assistant,
+        MessageContent::ToolResult { .. } => role == MessageRole::Tool,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(ApiError::InvalidInput(format!("Content does not match role {}", role.name())))
+    }
+}
+
+/// Dodaje wiadomość z jawną rolą i treścią do aktywnej gałęzi; zwraca id nowej wiadomości.
+#[update(guard = "not_banned")]
+fn add_message(user: Principal, chat_id: [u8; 16], role: MessageRole, content: MessageContent, model: Option<String>, opts: Option<WriteOpts>) -> Result<u32, ApiError> {
+    authorize_user("add_message", user)?;
+    idempotent(user, "add_message", &opts, args_hash(&(chat_id, role, &content, &model)), || {
+        check_message_kind(role, &content)?;
+        validate_content(&content)?;
+        if let Some(model) = &model {
+            validate_role_text(model)?;
+        }
+        if let MessageContent::PixelImage { width, height, .. } = &content {
+            check_tier_image(user, *width, *height)?;
+        }
+        check_storage(user, content_bytes(&content))?;
+        // pula `Tool` liczy tylko zapisane wywołania narzędzi; nieudany zapis oddaje prompt
+        let tool_call = matches!(content, MessageContent::ToolCall { .. });
+        if tool_call && !inc_quota_stable(user, QuotaPool::Tool) {
+            return Err(ApiError::Busy("Quota for tool exceeded".to_string()));
+        }
+        let result = with_chat_version(user, chat_id, &opts, || {
+            let (_name, msg_count) = USER_CHATS_STABLE
+                .with(|map| map.borrow().get(&(user, chat_id)))
+                .ok_or_else(|| ApiError::NotFound("Chat not found".to_string()))?;
+            let parent = get_active_leaf(user, chat_id, msg_count);
+            insert_typed_message_stable(user, chat_id, parent, role, content, model, time())
+                .ok_or_else(|| ApiError::NotFound("Chat not found".to_string()))
+        });
+        if tool_call && result.is_err() {
+            refund_quota(user, QuotaPool::Tool);
+        }
+        result
+    })
+}
+
+/// Aktywna gałąź czatu jako wiadomości z typowaną rolą i treścią.
+#[query(guard = "not_banned")]
+fn get_messages(user: Principal, chat_id: [u8; 16]) -> Vec<TypedMessage> {
+    authorize_user_or_reject("get_messages", user);
+    get_typed_messages_stable(user, chat_id)
+}
+
+#[query(guard = "not_banned")]
 fn get_chat_history(user: Principal, chat_id: [u8; 16], msg_len: u32) -> ChatInfo {
+    authorize_user_or_reject("get_chat_history", user);
     get_msgs_for_user(user, chat_id, msg_len)
 }
 
-#[update]
-fn delete_chat(user: Principal, chat_id: [u8; 16]) -> bool {
-    delete_chat_stable(user, chat_id)
+/// Przenosi czat do kosza; zwraca nową wersję czatu.
+#[update(guard = "not_banned")]
+fn delete_chat(user: Principal, chat_id: [u8; 16], opts: Option<WriteOpts>) -> Result<u64, ApiError> {
+    authorize_user("delete_chat", user)?;
+    idempotent(user, "delete_chat", &opts, args_hash(&(chat_id,)), || {
+        with_chat_version(user, chat_id, &opts, || {
+            delete_chat_stable(user, chat_id)
+                .then_some(())
+                .ok_or_else(|| ApiError::NotFound("Chat not found".to_string()))
+        })
+        .map(|_| chat_version(user, chat_id))
+    })
 }
 
-#[update]
-fn rename_chat(user: Principal, chat_id: [u8; 16], new_name: String) -> bool {
-    rename_chat_stable(user, chat_id, new_name)
+#[update(guard = "not_banned")]
+fn rename_chat(user: Principal, chat_id: [u8; 16], new_name: String, opts: Option<WriteOpts>) -> Result<u64, ApiError> {
+    authorize_user("rename_chat", user)?;
+    idempotent(user, "rename_chat", &opts, args_hash(&(chat_id, &new_name)), || {
+        validate_chat_name(&new_name)?;
+        with_chat_version(user, chat_id, &opts, || {
+            rename_chat_stable(user, chat_id, new_name)
+                .then_some(())
+                .ok_or_else(|| ApiError::NotFound("Chat not found".to_string()))
+        })
+        .map(|_| chat_version(user, chat_id))
+    })
 }
 
-#[update]
-fn update_image(user: Principal, chat_id: [u8; 16], msg_id: u32, new_content: String) {
-    let _ = update_image_content(user, chat_id, msg_id, new_content.as_str());
+#[update(guard = "not_banned")]
+fn update_image(user: Principal, chat_id: [u8; 16], msg_id: u32, new_content: String, opts: Option<WriteOpts>) -> Result<u64, ApiError> {
+    authorize_user("update_image", user)?;
+    idempotent(user, "update_image", &opts, args_hash(&(chat_id, msg_id, &new_content)), || {
+        validate_image_data(&new_content)?;
+        check_storage(user, new_content.len() as u64)?;
+        with_chat_version(user, chat_id, &opts, || update_image_content(user, chat_id, msg_id, new_content.as_str(), None))
+            .map(|_| chat_version(user, chat_id))
+    })
 }
 
-#[query]
+#[query(guard = "not_banned")]
 fn list_chats(user: Principal, arch: bool) -> Vec<ChatMeta> {
+    authorize_user_or_reject("list_chats", user);
     if arch {
         get_archives_for_user(user)
     } else {
@@ -627,28 +5929,634 @@ fn list_chats(user: Principal, arch: bool) -> Vec<ChatMeta> {
     }
 }
 
-#[update]
-fn set_user_name(user: Principal, name: String) {
-    set_name_stable(user, name)
+#[update(guard = "not_banned")]
+fn set_user_name(user: Principal, name: String, opts: Option<WriteOpts>) -> Result<(), ApiError> {
+    authorize_user("set_user_name", user)?;
+    idempotent(user, "set_user_name", &opts, args_hash(&(&name,)), || {
+        validate_name("User name", &name, Setting::MaxUserNameBytes, 32)?;
+        set_name_stable(user, name);
+        Ok(())
+    })
 }
 
-#[query]
+#[query(guard = "not_banned")]
 fn get_user_name(user: Principal) -> String {
+    authorize_user_or_reject("get_user_name", user);
     get_name_stable(user).map(|b| fixed_bytes_to_string(&b))
     .unwrap_or_else(|| "anonimus".to_string())
 }
 
+#[update(guard = "not_banned")]
+fn try_increment_user_prompt(user: Principal, opts: Option<WriteOpts>) -> bool {
+    authorize_user_or_reject("try_increment_user_prompt", user);
+    idempotent(user, "try_increment_user_prompt", &opts, args_hash(&()), || inc_user_prompt_stable(user))
+}
+
+/// Rezerwuje prompt przed `chat`/`askaidraw`; wywołanie z id rezerwacji rozlicza ją samo.
+#[update(guard = "not_banned")]
+async fn reserve_prompt(user: Principal, pool: QuotaPool, opts: Option<WriteOpts>) -> Result<PromptReservation, ApiError> {
+    authorize_user("reserve_prompt", user)?;
+    let args = args_hash(&(pool,));
+    if let Some(result) = idempotent_begin(user, "reserve_prompt", &opts, args) {
+        return result;
+    }
+    let result = reserve_prompt_stable(user, pool, None).await;
+    idempotent_store(user, "reserve_prompt", &opts, args, &result);
+    result
+}
+
+#[query(guard = "not_banned")]
+fn get_my_tier(user: Principal) -> UserTier {
+    authorize_user_or_reject("get_my_tier", user);
+    let assignment = tier_assignment(user);
+    let tier = assignment.as_ref().map_or(Tier::Free, |a| a.tier);
+    UserTier { tier, assignment, limits: tier_limits(tier) }
+}
+
+#[query(guard = "not_banned")]
+fn list_tiers() -> Vec<(Tier, TierLimits)> {
+    authorize_or_reject("list_tiers");
+    Tier::ALL.iter().map(|&tier| (tier, tier_limits(tier))).collect()
+}
+
+/// Kupuje lub przedłuża plan za cykle dołączone do wywołania (tylko z innego canistra lub portfela).
+#[update(guard = "not_banned")]
+fn buy_tier(user: Principal, tier: Tier, opts: Option<WriteOpts>) -> Result<TierAssignment, ApiError> {
+    authorize_user("buy_tier", user)?;
+    idempotent(user, "buy_tier", &opts, args_hash(&(tier,)), || buy_tier_stable(user, tier))
+}
+
+#[query(guard = "not_banned")]
+fn get_quota_status(user: Principal) -> Vec<QuotaStatus> {
+    authorize_user_or_reject("get_quota_status", user);
+    quota_status(user)
+}
+
+#[update(guard = "not_banned")]
+fn archive_chat(user: Principal, chat_id: [u8; 16], archive: bool, opts: Option<WriteOpts>) -> Result<u64, ApiError> {
+    authorize_user("archive_chat", user)?;
+    idempotent(user, "archive_chat", &opts, args_hash(&(chat_id, archive)), || {
+        with_chat_version(user, chat_id, &opts, || {
+            set_chat_archived_stable(user, chat_id, archive)
+                .then_some(())
+                .ok_or_else(|| ApiError::NotFound("Chat not found".to_string()))
+        })
+        .map(|_| chat_version(user, chat_id))
+    })
+}
+
+#[query(guard = "not_banned")]
+fn get_all_images(user: Principal) -> ChatInfo {
+    authorize_user_or_reject("get_all_images", user);
+    get_all_images_for_user(user)
+}
+
+/// Generuje alternatywną odpowiedź dla `msg_id` na tym samym kontekście, bez zmiany gałęzi.
+#[update(guard = "not_banned")]
+async fn regenerate(user: Principal, chat_id: [u8; 16], msg_id: u32, tag: String, opts: Option<WriteOpts>, reservation: Option<[u8; 16]>) -> Result<u32, ApiError> {
+    authorize_user("regenerate", user)?;
+    regenerate_with(user, chat_id, msg_id, tag, opts, reservation, "regenerate", |_parent, content, tag| {
+        add_candidate_stable(user, chat_id, msg_id, tag, content)
+    })
+    .await
+}
+
+#[update(guard = "not_banned")]
+fn select_candidate(user: Principal, chat_id: [u8; 16], msg_id: u32, candidate: u32, opts: Option<WriteOpts>) -> Result<u64, ApiError> {
+    authorize_user("select_candidate", user)?;
+    idempotent(user, "select_candidate", &opts, args_hash(&(chat_id, msg_id, candidate)), || {
+        with_chat_version(user, chat_id, &opts, || select_candidate_stable(user, chat_id, msg_id, candidate))
+            .map(|_| chat_version(user, chat_id))
+    })
+}
+
+#[update(guard = "not_banned")]
+async fn fork_chat(user: Principal, chat_id: [u8; 16], up_to_msg_id: u32, new_name: String, opts: Option<WriteOpts>) -> Result<[u8; 16], ApiError> {
+    authorize_user("fork_chat", user)?;
+    let args = args_hash(&(chat_id, up_to_msg_id, &new_name));
+    if let Some(result) = idempotent_begin(user, "fork_chat", &opts, args) {
+        return result;
+    }
+    let result = match validate_chat_name(&new_name).and_then(|_| check_chat_count(user)) {
+        Ok(()) => match new_chat_id(user).await {
+            Ok(new_id) => fork_chat_stable(user, chat_id, up_to_msg_id, new_id, new_name),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+    idempotent_store(user, "fork_chat", &opts, args, &result);
+    result
+}
+
+#[update(guard = "not_banned")]
+fn edit_message(user: Principal, chat_id: [u8; 16], msg_id: u32, new_content: String, opts: Option<WriteOpts>) -> Result<u32, ApiError> {
+    authorize_user("edit_message", user)?;
+    idempotent(user, "edit_message", &opts, args_hash(&(chat_id, msg_id, &new_content)), || {
+        validate_text("Message", &new_content)?;
+        check_storage(user, new_content.len() as u64)?;
+        with_chat_version(user, chat_id, &opts, || edit_message_stable(user, chat_id, msg_id, new_content))
+    })
+}
+
+#[update(guard = "not_banned")]
+fn switch_branch(user: Principal, chat_id: [u8; 16], msg_id: u32, opts: Option<WriteOpts>) -> Result<u32, ApiError> {
+    authorize_user("switch_branch", user)?;
+    idempotent(user, "switch_branch", &opts, args_hash(&(chat_id, msg_id)), || {
+        with_chat_version(user, chat_id, &opts, || switch_branch_stable(user, chat_id, msg_id))
+    })
+}
+
+#[query(guard = "not_banned")]
+fn get_active_path(user: Principal, chat_id: [u8; 16]) -> Option<ChatPath> {
+    authorize_user_or_reject("get_active_path", user);
+    get_active_path_stable(user, chat_id)
+}
+
+/// Zmiany użytkownika po `seq`; klient zaczyna od 0 i zapamiętuje `seq` ostatniej zmiany.
+#[query(guard = "not_banned")]
+fn get_changes_since(user: Principal, seq: u64, limit: u32) -> ChangeFeed {
+    authorize_user_or_reject("get_changes_since", user);
+    get_changes_since_stable(user, seq, limit)
+}
+
+#[query(guard = "not_banned")]
+fn list_trash(user: Principal) -> Vec<TrashedChat> {
+    authorize_user_or_reject("list_trash", user);
+    get_trash_for_user(user)
+}
+
+#[update(guard = "not_banned")]
+fn restore_chat(user: Principal, chat_id: [u8; 16], opts: Option<WriteOpts>) -> Result<u64, ApiError> {
+    authorize_user("restore_chat", user)?;
+    idempotent(user, "restore_chat", &opts, args_hash(&(chat_id,)), || {
+        check_chat_count(user)?;
+        with_chat_version(user, chat_id, &opts, || {
+            restore_chat_stable(user, chat_id)
+                .then_some(())
+                .ok_or_else(|| ApiError::NotFound("Chat not found in trash".to_string()))
+        })
+        .map(|_| chat_version(user, chat_id))
+    })
+}
+
+/// Trwale usuwa jeden czat z kosza albo cały kosz, gdy `chat_id` jest puste.
+#[update(guard = "not_banned")]
+fn purge_now(user: Principal, chat_id: Option<[u8; 16]>, opts: Option<WriteOpts>) -> u32 {
+    authorize_user_or_reject("purge_now", user);
+    idempotent(user, "purge_now", &opts, args_hash(&(chat_id,)), || purge_trash_for_user(user, chat_id))
+}
+
+/// Otwiera upload treści większej niż limit jednego wywołania; `sha256` dotyczy całości.
+#[update(guard = "not_banned")]
+async fn begin_upload(user: Principal, target: UploadTarget, total_size: u64, sha256: [u8; 32], opts: Option<WriteOpts>) -> Result<UploadStatus, ApiError> {
+    authorize_user("begin_upload", user)?;
+    let args = args_hash(&(&target, total_size, sha256));
+    if let Some(result) = idempotent_begin(user, "begin_upload", &opts, args) {
+        return result;
+    }
+    let result = match check_upload_target(user, &target, total_size) {
+        Ok(()) => match new_upload_id(user).await {
+            Ok(upload_id) => begin_upload_stable(user, upload_id, target, total_size, sha256),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+    idempotent_store(user, "begin_upload", &opts, args, &result);
+    result
+}
+
+#[update(guard = "not_banned")]
+fn upload_chunk(user: Principal, upload_id: [u8; 16], index: u32, data: Vec<u8>) -> Result<UploadStatus, ApiError> {
+    authorize_user("upload_chunk", user)?;
+    upload_chunk_stable(user, upload_id, index, data)
+}
+
+#[update(guard = "not_banned")]
+fn commit_upload(user: Principal, upload_id: [u8; 16], opts: Option<WriteOpts>) -> Result<u32, ApiError> {
+    authorize_user("commit_upload", user)?;
+    idempotent(user, "commit_upload", &opts, args_hash(&(upload_id,)), || {
+        let chat_id = UPLOADS_STABLE
+            .with(|map| map.borrow().get(&(user, upload_id)))
+            .map(|upload| upload.target.chat_id())
+            .ok_or_else(|| ApiError::NotFound("Upload not found".to_string()))?;
+        with_chat_version(user, chat_id, &opts, || commit_upload_stable(user, upload_id))
+    })
+}
+
+#[update(guard = "not_banned")]
+fn abort_upload(user: Principal, upload_id: [u8; 16]) -> bool {
+    authorize_user_or_reject("abort_upload", user);
+    remove_upload(user, upload_id)
+}
+
+#[query(guard = "not_banned")]
+fn list_uploads(user: Principal) -> Vec<UploadStatus> {
+    authorize_user_or_reject("list_uploads", user);
+    list_uploads_for_user(user)
+}
+
+/// Otwiera pobieranie obrazu, czatu lub eksportu; fragmenty czyta się przez `get_download_chunk`.
+/// Eksport powstaje w tle: `get_download` pokazuje, kiedy jest `ready`.
+#[update(guard = "not_banned")]
+async fn open_download(user: Principal, source: DownloadSource) -> Result<DownloadHandle, ApiError> {
+    authorize_user("open_download", user)?;
+    let handle = new_download_handle(user).await?;
+    open_download_stable(user, handle, source)
+}
+
+/// Stan pobierania; eksport jest gotowy do czytania, gdy `ready`.
+#[query(guard = "not_banned")]
+fn get_download(user: Principal, handle: [u8; 16]) -> Result<DownloadHandle, ApiError> {
+    authorize_user("get_download", user)?;
+    get_download_stable(user, handle)
+}
+
+#[query(guard = "not_banned")]
+fn get_download_chunk(user: Principal, handle: [u8; 16], index: u32) -> Result<Vec<u8>, ApiError> {
+    authorize_user("get_download_chunk", user)?;
+    get_download_chunk_stable(user, handle, index)
+}
+
+#[update(guard = "not_banned")]
+fn close_download(user: Principal, handle: [u8; 16]) -> bool {
+    authorize_user_or_reject("close_download", user);
+    close_download_stable(user, handle)
+}
+
+/// Wersja, stan pamięci i dostępność LLM.
+#[query(guard = "not_banned")]
+fn status() -> CanisterStatus {
+    authorize_or_reject("status");
+    canister_status()
+}
+
+/// Bramka HTTP. Odpowiedzi nie są certyfikowane: obrazy serwujemy tylko przez `<canister-id>.raw.icp0.io`.
+#[query(guard = "not_banned")]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    authorize_or_reject("http_request");
+    let (path, params) = split_url(&req.url);
+    // endpointy API wymagają wywołania update (bramka ponawia je przez http_request_update)
+    if path.starts_with("/v1/") {
+        return HttpResponse { status_code: 200, headers: Vec::new(), body: Vec::new(), upgrade: Some(true) };
+    }
+    if req.method != "GET" {
+        return http_error(405, "Method not allowed");
+    }
+    if path.starts_with("/image/") {
+        // odczyt kluczem API zapisuje `last_used_at`, więc idzie przez update
+        if bearer_token(&req).is_some_and(|token| token.starts_with("sk-")) {
+            return HttpResponse { status_code: 200, headers: Vec::new(), body: Vec::new(), upgrade: Some(true) };
+        }
+        return http_image(&req, path, &params);
+    }
+    if path == "/metrics" {
+        return http_response(
+            200,
+            vec![
+                ("Content-Type".to_string(), "text/plain; version=0.0.4; charset=utf-8".to_string()),
+                ("Cache-Control".to_string(), "no-store".to_string()),
+            ],
+            render_metrics().into_bytes(),
+        );
+    }
+    http_error(404, "Not found")
+}
+
+/// Tworzy link do obrazu (albo do wszystkich obrazów czatu); token wraca tylko w tej odpowiedzi.
+#[update(guard = "not_banned")]
+async fn create_share_link(user: Principal, chat_id: [u8; 16], msg_id: Option<u32>, ttl_secs: Option<u64>, opts: Option<WriteOpts>) -> Result<ShareLink, ApiError> {
+    authorize_user("create_share_link", user)?;
+    let args = args_hash(&(chat_id, msg_id, ttl_secs));
+    if let Some(result) = idempotent_begin(user, "create_share_link", &opts, args) {
+        return result;
+    }
+    let result = match ensure_rng_seeded().await {
+        Ok(()) => match random_bytes() {
+            Some(bytes) => create_share_link_stable(user, hex_encode(&bytes), chat_id, msg_id, ttl_secs),
+            None => Err(ApiError::Internal("Random generator not seeded".to_string())),
+        },
+        Err(e) => Err(e),
+    };
+    idempotent_store(user, "create_share_link", &opts, args, &result);
+    result
+}
+
+/// Token właściciela do obrazów czatu przez HTTP (`?token=`), ważny kwadrans.
+#[update(guard = "not_banned")]
+async fn create_image_token(user: Principal, chat_id: [u8; 16]) -> Result<ImageToken, ApiError> {
+    authorize_user("create_image_token", user)?;
+    ensure_rng_seeded().await?;
+    let bytes = random_bytes().ok_or_else(|| ApiError::Internal("Random generator not seeded".to_string()))?;
+    create_image_token_stable(user, hex_encode(&bytes), chat_id)
+}
+
+#[query(guard = "not_banned")]
+fn list_share_links(user: Principal) -> Vec<ShareLink> {
+    authorize_user_or_reject("list_share_links", user);
+    list_share_links_for_user(user)
+}
+
+#[update(guard = "not_banned")]
+fn revoke_share_link(user: Principal, id: [u8; 32]) -> bool {
+    authorize_user_or_reject("revoke_share_link", user);
+    revoke_share_link_stable(user, id)
+}
+
+#[update(guard = "not_banned")]
+async fn http_request_update(req: HttpRequest) -> HttpResponse {
+    authorize_or_reject("http_request_update");
+    let (path, params) = split_url(&req.url);
+    match (req.method.as_str(), path) {
+        ("GET", path) if path.starts_with("/image/") => {
+            let response = http_image(&req, path, &params);
+            if response.status_code != 401 {
+                if let Some(token) = bearer_token(&req) {
+                    let _ = record_api_key_use(token);
+                }
+            }
+            response
+        }
+        ("POST", "/v1/chat/completions") => openai_chat_completions(&req).await,
+        (_, "/v1/chat/completions") => openai_error(405, "invalid_request_error", "Method not allowed"),
+        _ => openai_error(404, "invalid_request_error", "Not found"),
+    }
+}
+
+/// Tworzy klucz API; token wraca tylko w tej odpowiedzi, canister trzyma jego hash.
+#[update(guard = "not_banned")]
+async fn create_api_key(user: Principal, name: String, scope: ApiKeyScope, daily_limit: Option<u32>, opts: Option<WriteOpts>) -> Result<ApiKey, ApiError> {
+    authorize_user("create_api_key", user)?;
+    let args = args_hash(&(&name, scope, daily_limit));
+    if let Some(result) = idempotent_begin(user, "create_api_key", &opts, args) {
+        return result;
+    }
+    let result = match ensure_rng_seeded().await {
+        Ok(()) => match random_bytes() {
+            Some(bytes) => create_api_key_stable(user, format!("sk-{}", hex_encode(&bytes)), name, scope, daily_limit),
+            None => Err(ApiError::Internal("Random generator not seeded".to_string())),
+        },
+        Err(e) => Err(e),
+    };
+    idempotent_store(user, "create_api_key", &opts, args, &result);
+    result
+}
+
+#[query(guard = "not_banned")]
+fn list_api_keys(user: Principal) -> Vec<ApiKey> {
+    authorize_user_or_reject("list_api_keys", user);
+    list_api_keys_for_user(user)
+}
+
+#[update(guard = "not_banned")]
+fn revoke_api_key(user: Principal, id: [u8; 32]) -> bool {
+    authorize_user_or_reject("revoke_api_key", user);
+    revoke_api_key_stable(user, id)
+}
+
+#[update]
+fn set_setting(setting: Setting, value: u64) -> Result<(), ApiError> {
+    authorize("set_setting")?;
+    set_setting_stable(setting, value);
+    Ok(())
+}
+
+#[query]
+fn admin_list_users(after: Option<Principal>, limit: u32) -> Result<UserPage, ApiError> {
+    authorize("admin_list_users")?;
+    Ok(list_users_page(after, limit))
+}
+
+#[update]
+fn admin_reset_prompt_quota(user: Principal) -> Result<(), ApiError> {
+    authorize("admin_reset_prompt_quota")?;
+    reset_prompt_quota_stable(user);
+    Ok(())
+}
+
 #[update]
-pub fn try_increment_user_prompt(user: Principal) -> bool {
-    inc_user_prompt_stable(user)
+fn admin_set_prompt_limit(user: Principal, limit: Option<u32>) -> Result<(), ApiError> {
+    authorize("admin_set_prompt_limit")?;
+    set_prompt_limit_stable(user, limit);
+    Ok(())
 }
 
 #[update]
-fn archive_chat(user: Principal, chat_id: [u8; 16], archive: bool) -> bool {
-    set_chat_archived_stable(user, chat_id, archive)
+fn admin_ban_user(user: Principal, reason: String) -> Result<(), ApiError> {
+    authorize("admin_ban_user")?;
+    ban_user_stable(user, reason)
+}
+
+#[update]
+fn admin_unban_user(user: Principal) -> Result<bool, ApiError> {
+    authorize("admin_unban_user")?;
+    Ok(BANNED_STABLE.with(|map| map.borrow_mut().remove(&user)).is_some())
 }
 
 #[query]
-fn get_all_images(user: Principal) -> ChatInfo {
-    get_all_images_for_user(user)
-}
\ No newline at end of file
+fn admin_usage_rollup(range: TimeRange, after: Option<Principal>, limit: u32) -> Result<UsageRollup, ApiError> {
+    authorize("admin_usage_rollup")?;
+    Ok(usage_rollup(&range, after, limit))
+}
+
+/// Dzienne sumy i wpisy dziennika użytkownika z zakresu czasu (ten sam principal, pod którym liczone jest zużycie).
+#[query(guard = "not_banned")]
+fn get_my_usage(user: Principal, range: TimeRange) -> UsageReport {
+    authorize_user_or_reject("get_my_usage", user);
+    usage_report(user, &range)
+}
+
+#[update]
+fn admin_set_user_tier(user: Principal, tier: Tier, expires_at: Option<u64>) -> Result<TierAssignment, ApiError> {
+    authorize("admin_set_user_tier")?;
+    Ok(assign_tier_stable(user, tier, TierSource::Controller, expires_at))
+}
+
+#[update]
+fn admin_set_tier_limits(tier: Tier, limits: TierLimits) -> Result<(), ApiError> {
+    authorize("admin_set_tier_limits")?;
+    validate_tier_limits(&limits)?;
+    TIER_LIMITS_STABLE.with(|map| map.borrow_mut().insert(tier.key(), limits));
+    Ok(())
+}
+
+/// Usuwa dane użytkownika tym samym zadaniem w tle co `delete_my_account`.
+#[update]
+fn admin_delete_user_data(user: Principal) -> Result<DeletionProgress, ApiError> {
+    authorize("admin_delete_user_data")?;
+    Ok(start_account_deletion(user))
+}
+
+/// Przegląd spójności magazynów porcjami; z `repair` poprawia liczniki i usuwa lub podpina sieroty.
+#[update]
+fn verify_integrity(cursor: Option<IntegrityCursor>, limit: u32, repair: bool) -> Result<IntegrityReport, ApiError> {
+    authorize("verify_integrity")?;
+    Ok(verify_integrity_batch(cursor, limit, repair))
+}
+
+#[query(guard = "not_banned")]
+fn get_setting_value(setting: Setting) -> u64 {
+    authorize_or_reject("get_setting_value");
+    get_setting(setting)
+}
+
+/// Eksport wszystkich danych wywołującego, porcjami po najwyżej `limit` rekordów.
+#[query(guard = "not_banned")]
+fn export_my_data(cursor: Option<ExportCursor>, limit: u32) -> ExportChunk {
+    authorize_or_reject("export_my_data");
+    export_user_data(ic_cdk::caller(), cursor, limit)
+}
+
+/// Usuwa wszystkie dane wywołującego; praca idzie porcjami w timerach, postęp w `get_account_deletion_progress`.
+#[update(guard = "not_banned")]
+fn delete_my_account(opts: Option<WriteOpts>) -> Result<DeletionProgress, ApiError> {
+    authorize("delete_my_account")?;
+    let user = ic_cdk::caller();
+    idempotent(user, "delete_my_account", &opts, args_hash(&()), || Ok(start_account_deletion(user)))
+}
+
+#[query(guard = "not_banned")]
+fn get_account_deletion_progress() -> Option<DeletionProgress> {
+    authorize_or_reject("get_account_deletion_progress");
+    get_deletion_progress(ic_cdk::caller())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(width: u32, height: u32, data: &str) -> StoredImage {
+        StoredImage { width, height, data: data.as_bytes().to_vec() }
+    }
+
+    /// Rozbija PNG na fragmenty (typ, dane), sprawdzając sygnaturę i CRC każdego.
+    fn png_chunks(png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        assert_eq!(&png[..8], &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]);
+        let mut chunks = Vec::new();
+        let mut i = 8;
+        while i < png.len() {
+            let len = u32::from_be_bytes(png[i..i + 4].try_into().unwrap()) as usize;
+            let kind: [u8; 4] = png[i + 4..i + 8].try_into().unwrap();
+            let data = png[i + 8..i + 8 + len].to_vec();
+            let crc = u32::from_be_bytes(png[i + 8 + len..i + 12 + len].try_into().unwrap());
+            assert_eq!(crc, crc32(&[&kind, &data]), "CRC of {:?}", std::str::from_utf8(&kind));
+            chunks.push((kind, data));
+            i += 12 + len;
+        }
+        assert_eq!(i, png.len());
+        chunks
+    }
+
+    /// Rozpakowuje strumień zlib złożony z bloków deflate "stored" i sprawdza adler32.
+    fn inflate_stored(zlib: &[u8]) -> Vec<u8> {
+        assert_eq!(&zlib[..2], &[0x78, 0x01]);
+        let mut out = Vec::new();
+        let mut i = 2;
+        loop {
+            let last = zlib[i] & 1 == 1;
+            assert_eq!(zlib[i] >> 1, 0, "only stored blocks are expected");
+            let len = u16::from_le_bytes([zlib[i + 1], zlib[i + 2]]);
+            let nlen = u16::from_le_bytes([zlib[i + 3], zlib[i + 4]]);
+            assert_eq!(len, !nlen);
+            out.extend_from_slice(&zlib[i + 5..i + 5 + len as usize]);
+            i += 5 + len as usize;
+            if last {
+                break;
+            }
+        }
+        assert_eq!(&zlib[i..], &adler32(&out).to_be_bytes());
+        out
+    }
+
+    #[test]
+    fn crc32_and_adler32_match_known_vectors() {
+        assert_eq!(crc32(&[b"123456789"]), 0xCBF4_3926);
+        assert_eq!(crc32(&[b"1234", b"56789"]), 0xCBF4_3926);
+        assert_eq!(crc32(&[b"IEND"]), 0xAE42_6082);
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        // dłużej niż 5552 bajty: sumy muszą być redukowane co blok
+        assert_eq!(adler32(&[0xFF; 6000]), 0xA497_59EA);
+    }
+
+    #[test]
+    fn png_has_header_pixels_and_end() {
+        let png = render_png(&image(2, 1, "|y:1,x:1;#FF0000||y:1,x:2;#00FF00|"), 1);
+        let chunks = png_chunks(&png);
+        let kinds: Vec<&[u8; 4]> = chunks.iter().map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, vec![b"IHDR", b"IDAT", b"IEND"]);
+
+        let ihdr = &chunks[0].1;
+        assert_eq!(ihdr.len(), 13);
+        assert_eq!(u32::from_be_bytes(ihdr[0..4].try_into().unwrap()), 2);
+        assert_eq!(u32::from_be_bytes(ihdr[4..8].try_into().unwrap()), 1);
+        assert_eq!(&ihdr[8..], &[8, 6, 0, 0, 0]);
+
+        // siatka jest odbita jak na froncie: pierwszy zapisany kolor trafia na prawą krawędź
+        let rows = inflate_stored(&chunks[1].1);
+        assert_eq!(rows, vec![0, 0, 255, 0, 255, 255, 0, 0, 255]);
+        assert!(chunks[2].1.is_empty());
+        assert_eq!(&png[png.len() - 4..], &[0xAE, 0x42, 0x60, 0x82]);
+    }
+
+    #[test]
+    fn png_scales_and_splits_large_images_into_stored_blocks() {
+        let png = render_png(&image(128, 128, "#123456"), 1);
+        let chunks = png_chunks(&png);
+        let rows = inflate_stored(&chunks[1].1);
+        assert_eq!(rows.len(), (128 * 4 + 1) * 128);
+        // brakujące piksele są przezroczyste, jedyny zapisany leży w prawym dolnym rogu
+        let last_row = &rows[rows.len() - (128 * 4 + 1)..];
+        assert_eq!(last_row[0], 0);
+        assert_eq!(&last_row[last_row.len() - 4..], &[0x12, 0x34, 0x56, 255]);
+        assert_eq!(&last_row[1..5], &[0, 0, 0, 0]);
+
+        let scaled = png_chunks(&render_png(&image(1, 1, "#ABCDEF"), 3));
+        assert_eq!(u32::from_be_bytes(scaled[0].1[0..4].try_into().unwrap()), 3);
+        assert_eq!(inflate_stored(&scaled[1].1).len(), (3 * 4 + 1) * 3);
+    }
+
+    #[test]
+    fn svg_merges_runs_of_equal_pixels() {
+        let svg = String::from_utf8(render_svg(&image(3, 1, "#FF0000#FF0000#00FF00"), 4)).unwrap();
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 3 1\" width=\"12\" height=\"4\""));
+        assert!(svg.contains("<rect x=\"0\" y=\"0\" width=\"1\" height=\"1\" fill=\"#00ff00\"/>"));
+        assert!(svg.contains("<rect x=\"1\" y=\"0\" width=\"2\" height=\"1\" fill=\"#ff0000\"/>"));
+        assert!(svg.ends_with("</svg>"));
+        assert_eq!(render_svg(&image(2, 2, ""), 1), b"<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 2 2\" width=\"2\" height=\"2\" shape-rendering=\"crispEdges\"></svg>");
+    }
+
+    #[test]
+    fn render_scale_stays_within_pixel_budget() {
+        assert_eq!(render_scale(0, 0, None), DEFAULT_RENDER_SCALE);
+        assert_eq!(render_scale(1, 1, Some(0)), 1);
+        let max_side = Setting::MaxImageSide.default_value() as u32;
+        let scale = render_scale(max_side, max_side, Some(u32::MAX));
+        assert!(scale >= 1);
+        assert!((max_side as u64 * scale as u64).pow(2) <= MAX_RENDER_PIXELS);
+    }
+
+    #[test]
+    fn truncate_utf8_cuts_on_char_boundaries() {
+        assert_eq!(truncate_utf8("abc", 3), "abc");
+        assert_eq!(truncate_utf8("abc", 10), "abc");
+        assert_eq!(truncate_utf8("abc", 0), "");
+        // "ż" i "ó" mają po dwa bajty
+        assert_eq!(truncate_utf8("żółw", 3), "ż");
+        assert_eq!(truncate_utf8("żółw", 4), "żó");
+        assert_eq!(truncate_utf8("🙂x", 3), "");
+        assert_eq!(fixed_bytes_to_string(&string_to_fixed_bytes::<5>("aż😀")), "aż");
+    }
+
+    #[test]
+    fn validate_image_checks_size_edges() {
+        let max_side = Setting::MaxImageSide.default_value() as u32;
+        assert!(validate_image(0, 0, "").is_err());
+        assert!(validate_image(0, 1, "").is_err());
+        assert!(validate_image(1, 0, "").is_err());
+        assert!(validate_image(1, 1, "#FFFFFF").is_ok());
+        assert!(validate_image(max_side, max_side, "").is_ok());
+        assert!(validate_image(max_side + 1, 1, "").is_err());
+        assert!(validate_image(1, u32::MAX, "").is_err());
+        assert!(validate_image(1, 1, "#FF\u{0}000").is_err());
+    }
+}
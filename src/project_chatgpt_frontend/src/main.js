@@ -62,7 +62,7 @@ export const generate = async (ctxName, cols, rows) => {
     loginStatus.value.principal,
     current.value,
     temp,
-    'user', cols, rows, user_date
+    'user', cols, rows
   );
   messages.value.push(userMsg);
   chats.value.find(c => c.id === current.value).msg_len += 1;
@@ -81,6 +81,27 @@ export const chats = ref([]);
 export const archives = ref([]);
 export const current = ref(null);
 
+// Modele (ic_llm) dostępne w planie użytkownika; null = jeszcze nie wczytane.
+export const allowedModels = ref(null);
+
+const modelIds = {
+  Llama3_1_8B: 'llama3.1:8b',
+  Qwen3_32B: 'qwen3:32b',
+  Llama4Scout: 'llama4-scout',
+  Llama3_1_8B_Image: 'llama3.1:8b',
+  Llama4Scout_Image: 'llama4-scout',
+};
+
+export const modelAllowed = (tag) => allowedModels.value === null || allowedModels.value.includes(modelIds[tag]);
+
+export const loadTier = async () => {
+  const tier = await project_chatgpt_backend.get_my_tier(loginStatus.value.principal);
+  allowedModels.value = tier.limits.models;
+};
+
+// Backend odpowiada tym tekstem, gdy plan nie obejmuje wybranego modelu.
+const MODEL_NOT_ALLOWED = 'ERR_MODEL_NOT_ALLOWED';
+
 export const loginStatus = ref({
   loggedIn: false,
   principal: null,
@@ -102,6 +123,7 @@ export const login = async () => {
       loginStatus.value.loggedIn = true;
       loginStatus.value.principal = principal;
       loginStatus.value.username = await getUserName(loginStatus.value.principal);
+      await loadTier();
       alert("Logged in as " + loginStatus.value.username);
     },
     onError: (err) => {
@@ -122,9 +144,12 @@ export const logout = async () => {
   messages.value = [];
 };
 
+// Klucz idempotencji dla metod zapisujących (ponowienie nie zapisze drugi raz).
+const writeOpts = () => [{ request_key: [crypto.randomUUID()], expected_version: [] }];
+
 export const rename = async (new_username) => {
   loginStatus.value.username = new_username;
-  await project_chatgpt_backend.set_user_name(loginStatus.value.principal, new_username);
+  await project_chatgpt_backend.set_user_name(loginStatus.value.principal, new_username, writeOpts());
 };
 
 export const chat = async (message, tag) => {
@@ -144,7 +169,7 @@ export const chat = async (message, tag) => {
       'user',
       0,
       0,
-      user_date
+      writeOpts()
     );
     messages.value.push({ role: 'user', content: message, etc: [user_date, 0, 0] });
     nextTick(() => scrollToBottom());
@@ -161,7 +186,13 @@ export const chat = async (message, tag) => {
 
     let response;
     try {
-      response = await project_chatgpt_backend.chat(message, tag, history);
+      const reservation = await reservePrompt(loginStatus.value.principal, { Chat: null });
+      response = reservation
+        ? await project_chatgpt_backend.chat(message, tag, history, [reservation])
+        : "Daily limit reached. Come back tomorrow!";
+      if (response === MODEL_NOT_ALLOWED) {
+        response = "This model is not available on your plan.";
+      }
     } catch (err) {
       console.error("AI response error:", err);
       if (err.message && err.message.toLowerCase().includes("timeout")) {
@@ -189,7 +220,7 @@ export const chat = async (message, tag) => {
       tag,
       0,
       0,
-      ai_date
+      writeOpts()
     );
 
     // Aktualizuj liczbę wiadomości w chatcie
@@ -242,18 +273,18 @@ export const open = async (id) => {
 
 export const create = async () => {
   if (!loginStatus.value.loggedIn) return;
-  const uuid = crypto.randomUUID();
-  const bytes = Uint8Array.from(
-    uuid.replace(/-/g, '').match(/.{2}/g).map(b => parseInt(b, 16))
-  );
   const name = `New Chat ${chats.value.length + 1}`;
-  await project_chatgpt_backend.create_new_chat(loginStatus.value.principal, bytes, name);
+  const result = await project_chatgpt_backend.create_new_chat(loginStatus.value.principal, name, writeOpts());
+  if ('Err' in result) {
+    console.error("Create chat error:", result.Err);
+    return;
+  }
   await load();
-  current.value = bytes;
+  current.value = result.Ok;
 }
 
 export const remove_chat = async (id) => {
-  await project_chatgpt_backend.delete_chat(loginStatus.value.principal, id);
+  await project_chatgpt_backend.delete_chat(loginStatus.value.principal, id, writeOpts());
   if (current.value === id) {
     current.value = null;
     messages.value = [];
@@ -262,12 +293,12 @@ export const remove_chat = async (id) => {
 }
 
 export const rename_chat = async (id, new_name) => {
-  await project_chatgpt_backend.rename_chat(loginStatus.value.principal, id, new_name);
+  await project_chatgpt_backend.rename_chat(loginStatus.value.principal, id, new_name, writeOpts());
   await load();
 }
 
 export const archive_chat = async (id, archive) => {
-  await project_chatgpt_backend.archive_chat(loginStatus.value.principal, id, archive);
+  await project_chatgpt_backend.archive_chat(loginStatus.value.principal, id, archive, writeOpts());
   if (current.value === id) {
     current.value = null;
     messages.value = [];
@@ -277,27 +308,35 @@ export const archive_chat = async (id, archive) => {
 
 
 export async function updateImage(msgId, new_content) {
-  return await project_chatgpt_backend.update_image(loginStatus.value.principal, current.value, msgId, new_content);
+  return await project_chatgpt_backend.update_image(loginStatus.value.principal, current.value, msgId, new_content, writeOpts());
 }
 
 export async function askAiDraw(query, tag, msg) {
-  return await project_chatgpt_backend.askaidraw(query, tag, msg);
+  const reservation = await reservePrompt(loginStatus.value.principal, { Drawing: null });
+  if (!reservation) {
+    throw new Error("Daily drawing limit reached. Come back tomorrow!");
+  }
+  const response = await project_chatgpt_backend.askaidraw(query, tag, msg, [reservation]);
+  if (response === MODEL_NOT_ALLOWED) {
+    throw new Error("This model is not available on your plan.");
+  }
+  return response;
 }
 
 export async function archiveChat(principal, chatId, archive) {
-  return await project_chatgpt_backend.archive_chat(principal, chatId, archive);
+  return await project_chatgpt_backend.archive_chat(principal, chatId, archive, writeOpts());
 }
 
-export async function chatWithBackend(message, width, height, tag, principal, chatId, msgLen) {
-  return await project_chatgpt_backend.chat(message, width, height, tag, principal, chatId, msgLen);
+export async function chatWithBackend(message, tag, history, reservation) {
+  return await project_chatgpt_backend.chat(message, tag, history, [reservation]);
 }
 
-export async function createNewChat(principal, chatId, name) {
-  return await project_chatgpt_backend.create_new_chat(principal, chatId, name);
+export async function createNewChat(principal, name) {
+  return await project_chatgpt_backend.create_new_chat(principal, name, writeOpts());
 }
 
-export async function addChatMessage(principal, chatId, content, role, width, height, date) {
-  return await project_chatgpt_backend.add_chat_message(principal, chatId, content, role, width, height, date);
+export async function addChatMessage(principal, chatId, content, role, width, height) {
+  return await project_chatgpt_backend.add_chat_message(principal, chatId, content, role, width, height, writeOpts());
 }
 
 export async function getChatHistory(principal, chatId, msgLen) {
@@ -305,11 +344,11 @@ export async function getChatHistory(principal, chatId, msgLen) {
 }
 
 export async function deleteChat(principal, chatId) {
-  return await project_chatgpt_backend.delete_chat(principal, chatId);
+  return await project_chatgpt_backend.delete_chat(principal, chatId, writeOpts());
 }
 
 export async function renameChat(principal, chatId, newName) {
-  return await project_chatgpt_backend.rename_chat(principal, chatId, newName);
+  return await project_chatgpt_backend.rename_chat(principal, chatId, newName, writeOpts());
 }
 
 export async function listChats(principal, arch) {
@@ -317,15 +356,18 @@ export async function listChats(principal, arch) {
 }
 
 export async function setUserName(principal, username) {
-  return await project_chatgpt_backend.set_user_name(principal, username);
+  return await project_chatgpt_backend.set_user_name(principal, username, writeOpts());
 }
 
 export async function getUserName(principal) {
   return await project_chatgpt_backend.get_user_name(principal);
 }
 
-export async function tryPrompt(principal) {
-  return await project_chatgpt_backend.try_increment_user_prompt(principal);
+// Rezerwuje prompt z puli; backend rozlicza go sam w `chat`/`askaidraw` (niepowodzenie go oddaje).
+// Zwraca id rezerwacji albo null, gdy limit jest wyczerpany.
+export async function reservePrompt(principal, pool) {
+  const result = await project_chatgpt_backend.reserve_prompt(principal, pool, writeOpts());
+  return 'Ok' in result ? result.Ok.id : null;
 }
 
 export function getRandomUserMessages() {